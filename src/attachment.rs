@@ -0,0 +1,334 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use futures_core::Stream;
+use futures_util::stream::{self, TryStreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::error::{Error, Result};
+
+/**
+ * An attachment, as returned by the "users.messages.attachments.get" call.
+ */
+#[derive(Debug, Deserialize)]
+pub(crate) struct RAttachment {
+    pub size: u64,
+    #[serde(default)]
+    pub data: String,
+}
+
+/**
+ * The response to an attachment request is a JSON object with the attachment
+ * contents encoded as URL-safe base64 in the "data" property.  So that large
+ * attachments need not be held in memory, this decoder picks the base64 data
+ * out of the response as it arrives and decodes it incrementally.  The rest
+ * of the object, with an empty "data" value, is kept so that we can check the
+ * size once the response is complete.
+ */
+#[derive(Default)]
+struct Decoder {
+    in_data: bool,
+    seen_data: bool,
+    in_str: bool,
+    escape: bool,
+    after_colon: bool,
+    cur: Vec<u8>,
+    key: Vec<u8>,
+    rest: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    /**
+     * Process the next chunk of the response, returning any data we were
+     * able to decode.
+     */
+    fn feed(&mut self, mut chunk: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+
+        while !chunk.is_empty() {
+            if self.in_data {
+                /*
+                 * URL-safe base64 does not include any characters that need
+                 * to be escaped in a JSON string, so the data ends at the
+                 * next double quote.
+                 */
+                let end = chunk.iter().position(|&b| b == b'"');
+                let (data, more) = chunk.split_at(end.unwrap_or(chunk.len()));
+                self.pending.extend(
+                    data.iter()
+                        .filter(|b| !b.is_ascii_whitespace() && **b != b'='),
+                );
+                chunk = if end.is_some() { &more[1..] } else { more };
+
+                let n = if end.is_some() {
+                    self.in_data = false;
+                    self.pending.len()
+                } else {
+                    self.pending.len() - self.pending.len() % 4
+                };
+                if n > 0 {
+                    out.extend(
+                        base64::decode_config(
+                            &self.pending[..n],
+                            base64::URL_SAFE_NO_PAD,
+                        )
+                        .map_err(|e| {
+                            Error::Decode(format!("attachment data: {}", e))
+                        })?,
+                    );
+                    self.pending.drain(..n);
+                }
+                continue;
+            }
+
+            let b = chunk[0];
+            chunk = &chunk[1..];
+            self.rest.push(b);
+
+            if self.in_str {
+                if self.escape {
+                    self.escape = false;
+                    self.cur.push(b);
+                } else if b == b'\\' {
+                    self.escape = true;
+                } else if b == b'"' {
+                    self.in_str = false;
+                } else {
+                    self.cur.push(b);
+                }
+            } else if b == b'"' {
+                if self.after_colon && self.key == b"data" {
+                    if self.seen_data {
+                        return Err(Error::Decode(
+                            "attachment has more than one data value".into(),
+                        ));
+                    }
+                    self.seen_data = true;
+                    self.in_data = true;
+                    self.rest.push(b'"');
+                } else {
+                    self.in_str = true;
+                    self.cur.clear();
+                }
+                self.after_colon = false;
+            } else if b == b':' {
+                self.after_colon = true;
+                self.key = std::mem::take(&mut self.cur);
+            } else if !b.is_ascii_whitespace() {
+                self.after_colon = false;
+            }
+        }
+
+        Ok(out)
+    }
+
+    /**
+     * Check that the response was complete, and return the attachment with
+     * the data elided.
+     */
+    fn finish(self) -> Result<RAttachment> {
+        if self.in_data || !self.seen_data {
+            return Err(Error::Decode(
+                "attachment response did not include complete data".into(),
+            ));
+        }
+
+        serde_json::from_slice(&self.rest)
+            .map_err(|e| Error::decode(e, &self.rest))
+    }
+}
+
+/**
+ * Decode the attachment in a response, writing it out as it arrives.  If a
+ * maximum size is specified, we stop as soon as the attachment is larger than
+ * that.  Returns the size of the attachment.
+ */
+pub(crate) async fn write_response<W>(
+    res: reqwest::Response,
+    w: &mut W,
+    max_size: Option<u64>,
+) -> Result<u64>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let chunks = stream::try_unfold(res, |mut res| async move {
+        Ok(res.chunk().await?.map(|chunk| (chunk, res)))
+    });
+
+    write_chunks(chunks, w, max_size).await
+}
+
+/**
+ * Decode the attachment in a response body that arrives as a stream of
+ * chunks, as per write_response().
+ */
+async fn write_chunks<S, B, W>(
+    chunks: S,
+    w: &mut W,
+    max_size: Option<u64>,
+) -> Result<u64>
+where
+    S: Stream<Item = Result<B>>,
+    B: AsRef<[u8]>,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut dec = Decoder::default();
+    let mut total: u64 = 0;
+
+    futures_util::pin_mut!(chunks);
+    while let Some(chunk) = chunks.try_next().await? {
+        let buf = dec.feed(chunk.as_ref())?;
+        if buf.is_empty() {
+            continue;
+        }
+
+        total += buf.len() as u64;
+        if let Some(max) = max_size {
+            if total > max {
+                return Err(Error::Decode(format!(
+                    "attachment is larger than the maximum of {} bytes",
+                    max
+                )));
+            }
+        }
+
+        w.write_all(&buf).await?;
+    }
+
+    let ra = dec.finish()?;
+    if ra.size != total {
+        return Err(Error::Decode(format!(
+            "attachment was {} bytes, but expected {} bytes",
+            total, ra.size
+        )));
+    }
+
+    w.flush().await?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Decode a response that arrives in the specified chunks, returning the
+     * data that was written out and the reported size.
+     */
+    async fn decode(
+        chunks: &[&[u8]],
+        max_size: Option<u64>,
+    ) -> Result<(Vec<u8>, u64)> {
+        let mut out: Vec<u8> = Vec::new();
+        let chunks = stream::iter(chunks.iter().map(Ok));
+        let size = write_chunks(chunks, &mut out, max_size).await?;
+        Ok((out, size))
+    }
+
+    /**
+     * Check that a response decodes correctly, however it is split into two
+     * chunks, and when it arrives a byte at a time.
+     */
+    async fn check_splits(res: &str, data: &[u8]) {
+        let res = res.as_bytes();
+        let size = data.len() as u64;
+
+        for i in 0..=res.len() {
+            let (out, n) = decode(&[&res[..i], &res[i..]], None).await.unwrap();
+            assert_eq!(out, data, "split at {}", i);
+            assert_eq!(n, size);
+        }
+
+        let bytes = res.chunks(1).collect::<Vec<_>>();
+        let (out, n) = decode(&bytes, None).await.unwrap();
+        assert_eq!(out, data);
+        assert_eq!(n, size);
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn key_order() {
+        /*
+         * Use a length that is not a multiple of three, so that the encoded
+         * data needs padding.
+         */
+        let data = data(100);
+        for config in [base64::URL_SAFE, base64::URL_SAFE_NO_PAD] {
+            let enc = base64::encode_config(&data, config);
+
+            check_splits(
+                &format!(
+                    r#"{{"attachmentId": "ANGj", "size": 100, "data": "{}"}}"#,
+                    enc
+                ),
+                &data,
+            )
+            .await;
+            check_splits(
+                &format!(
+                    "{{\n  \"size\": 100,\n  \"data\": \"{}\",\n  \
+                    \"attachmentId\": \"ANGj\"\n}}\n",
+                    enc
+                ),
+                &data,
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn escaped_strings() {
+        /*
+         * A quote or a key name within another string must not be mistaken
+         * for the data.
+         */
+        let data = data(32);
+        let res = format!(
+            r#"{{"attachmentId": "x\"data\": \"", "size": 32, "data": "{}"}}"#,
+            base64::encode_config(&data, base64::URL_SAFE)
+        );
+        check_splits(&res, &data).await;
+    }
+
+    #[tokio::test]
+    async fn bad_responses() {
+        let enc = base64::encode_config(data(10), base64::URL_SAFE);
+        let bad = [
+            r#"{"attachmentId": "x", "size": 0}"#.to_string(),
+            format!(r#"{{"size": 10, "data": "{}", "data": "{}"}}"#, enc, enc),
+            format!(r#"{{"size": 11, "data": "{}"}}"#, enc),
+            format!(r#"{{"size": 10, "data": "{}"#, enc),
+            r#"{"size": 3, "data": "A*B="}"#.to_string(),
+        ];
+
+        for res in bad {
+            let res = res.as_bytes();
+            for i in 0..=res.len() {
+                let e = decode(&[&res[..i], &res[i..]], None).await;
+                assert!(matches!(e, Err(Error::Decode(_))), "{:?}", e);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn max_size() {
+        let data = data(3000);
+        let res = format!(
+            r#"{{"size": 3000, "data": "{}"}}"#,
+            base64::encode_config(&data, base64::URL_SAFE)
+        );
+        let chunks = res.as_bytes().chunks(100).collect::<Vec<_>>();
+
+        let (out, _) = decode(&chunks, Some(3000)).await.unwrap();
+        assert_eq!(out, data);
+
+        let e = decode(&chunks, Some(2999)).await;
+        assert!(matches!(e, Err(Error::Decode(_))), "{:?}", e);
+    }
+}
@@ -0,0 +1,704 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::{header, Method};
+use serde::{Deserialize, Serialize};
+use slog::{debug, trace, Logger};
+
+use super::error::{parse_retry_after, Error, Result};
+use super::gmail;
+use super::multipart::multipart_parse;
+
+const BOUNDARY: &str = "23121338-972e-11ea-a0c6-c3892af82e36";
+
+/**
+ * A single request within a batch.  The path is relative to the API base URL,
+ * as for the other API calls; e.g., "users/me/threads/{id}".
+ */
+#[derive(Debug, Clone)]
+pub struct BatchRequest {
+    method: Method,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+}
+
+impl BatchRequest {
+    pub fn new<S: AsRef<str>>(method: Method, path: S) -> BatchRequest {
+        BatchRequest {
+            method,
+            path: path.as_ref().to_string(),
+            query: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn get<S: AsRef<str>>(path: S) -> BatchRequest {
+        BatchRequest::new(Method::GET, path)
+    }
+
+    pub fn post<S: AsRef<str>>(path: S) -> BatchRequest {
+        BatchRequest::new(Method::POST, path)
+    }
+
+    pub fn delete<S: AsRef<str>>(path: S) -> BatchRequest {
+        BatchRequest::new(Method::DELETE, path)
+    }
+
+    /**
+     * Add a query parameter.  The same name may be used more than once; e.g.,
+     * for "metadataHeaders".
+     */
+    pub fn query<K: AsRef<str>, V: AsRef<str>>(
+        mut self,
+        name: K,
+        value: V,
+    ) -> BatchRequest {
+        self.query
+            .push((name.as_ref().to_string(), value.as_ref().to_string()));
+        self
+    }
+
+    /**
+     * Send this value, encoded as JSON, as the body of the request.
+     */
+    pub fn json<B: Serialize>(mut self, body: &B) -> Result<BatchRequest> {
+        self.body =
+            Some(serde_json::to_vec(body).map_err(|e| {
+                Error::Batch(format!("encoding request: {}", e))
+            })?);
+        Ok(self)
+    }
+}
+
+/**
+ * The response to a single request within a batch.
+ */
+#[derive(Debug)]
+pub struct BatchResponse {
+    index: usize,
+    status: u16,
+    content_type: Option<String>,
+    retry_after: Option<Duration>,
+    body: Vec<u8>,
+}
+
+impl BatchResponse {
+    /**
+     * The position of the corresponding request within the batch.
+     */
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /**
+     * How long the server asked us to wait before trying this request again,
+     * if it did.
+     */
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /**
+     * The error for this request, if it was not successful.
+     */
+    pub fn error(&self) -> Option<Error> {
+        if self.is_success() {
+            None
+        } else {
+            Some(Error::from_status(
+                self.status,
+                self.retry_after,
+                &self.body,
+            ))
+        }
+    }
+
+    /**
+     * Check that the request was successful, ignoring any response body;
+     * e.g., for a DELETE request.
+     */
+    pub fn check(&self) -> Result<()> {
+        match self.error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Check that the request was successful, and then decode the JSON body.
+     */
+    pub fn json<T>(&self) -> Result<T>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.check()?;
+
+        let ct: mime::Mime = self
+            .content_type
+            .as_deref()
+            .ok_or_else(|| {
+                Error::Batch("content type missing from part response".into())
+            })?
+            .parse()
+            .map_err(Error::batch)?;
+        match (ct.type_(), ct.subtype()) {
+            (mime::APPLICATION, mime::JSON) => (),
+            ct => {
+                return Err(Error::Batch(format!(
+                    "response part response had wrong type: {:?}",
+                    ct
+                )))
+            }
+        };
+
+        serde_json::from_slice(&self.body)
+            .map_err(|e| Error::decode(e, &self.body))
+    }
+}
+
+/**
+ * A set of requests to be made together in a single round trip to the batch
+ * endpoint.  Note that Gmail accepts at most 100 requests in a batch.
+ */
+pub struct Batch {
+    parent: Arc<gmail::GMailInner>,
+    requests: Vec<BatchRequest>,
+}
+
+impl Batch {
+    pub(crate) fn new(parent: &gmail::GMail) -> Batch {
+        Batch {
+            parent: Arc::clone(&parent.0),
+            requests: Vec::new(),
+        }
+    }
+
+    pub fn request(mut self, req: BatchRequest) -> Batch {
+        self.requests.push(req);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /**
+     * Make the requests.  The responses are returned in the same order as the
+     * requests were added.  An error is returned only if the batch as a whole
+     * failed; the outcome of each request is in its response.
+     */
+    pub async fn send(&self) -> Result<Vec<BatchResponse>> {
+        if self.requests.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        send(&self.parent, &self.requests).await
+    }
+}
+
+fn request_body(
+    parent: &gmail::GMailInner,
+    requests: &[BatchRequest],
+) -> Result<Vec<u8>> {
+    let mut body: Vec<u8> = Vec::new();
+
+    for (n, r) in requests.iter().enumerate() {
+        let mut path = parent.batch_path(&r.path);
+        if !r.query.is_empty() {
+            /*
+             * Use the URL parser to take care of encoding the query string.
+             */
+            let mut url = reqwest::Url::parse("http://batch/")
+                .map_err(|e| Error::Batch(format!("query: {}", e)))?;
+            url.query_pairs_mut().extend_pairs(&r.query);
+            path.push('?');
+            path.push_str(url.query().unwrap_or(""));
+        }
+
+        body.extend_from_slice(b"--");
+        body.extend_from_slice(BOUNDARY.as_bytes());
+        body.extend_from_slice(b"\r\n");
+
+        body.extend_from_slice(b"Content-Type: application/http\r\n");
+        body.extend_from_slice(format!("Content-ID: req-{}\r\n", n).as_bytes());
+        body.extend_from_slice(b"\r\n");
+
+        body.extend_from_slice(format!("{} {}\r\n", r.method, path).as_bytes());
+        if let Some(b) = &r.body {
+            body.extend_from_slice(b"Content-Type: application/json\r\n");
+            body.extend_from_slice(
+                format!("Content-Length: {}\r\n", b.len()).as_bytes(),
+            );
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(b);
+        } else {
+            body.extend_from_slice(b"\r\n");
+        }
+
+        body.extend_from_slice(b"\r\n");
+    }
+
+    body.extend_from_slice(b"--");
+    body.extend_from_slice(BOUNDARY.as_bytes());
+    body.extend_from_slice(b"--\r\n");
+
+    Ok(body)
+}
+
+/**
+ * Make a batch request, and return the response for each part in the same
+ * order as the requests.  The server may send the parts in any order, so we
+ * put each one in place using its Content-ID.
+ */
+pub(crate) async fn send(
+    parent: &gmail::GMailInner,
+    requests: &[BatchRequest],
+) -> Result<Vec<BatchResponse>> {
+    let log = &parent.log;
+    let url = parent.batch_url.to_string();
+
+    let buf = request_body(parent, requests)?;
+
+    trace!(log, "batch request: {:#?}", String::from_utf8_lossy(&buf));
+
+    /*
+     * The batch itself is always a POST, but it is only as safe to repeat as
+     * the requests within it.
+     */
+    let idempotent = requests.iter().all(|r| r.method.is_idempotent());
+
+    let res = parent
+        .execute_common(
+            || {
+                parent
+                    .client
+                    .post(&url)
+                    .header(
+                        header::CONTENT_TYPE,
+                        format!("multipart/mixed; boundary={}", BOUNDARY),
+                    )
+                    .body(buf.clone())
+            },
+            idempotent,
+        )
+        .await?;
+
+    let ct = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|ct| ct.to_str().map(str::to_string).map_err(Error::batch))
+        .transpose()?;
+    let body = res.bytes().await?;
+
+    parse_response(log, ct.as_deref(), &body, requests)
+}
+
+/**
+ * Parse the multipart response to a batch request, given its content type and
+ * body, and put the response for each part in the same order as the requests.
+ */
+fn parse_response(
+    log: &Logger,
+    content_type: Option<&str>,
+    x: &[u8],
+    requests: &[BatchRequest],
+) -> Result<Vec<BatchResponse>> {
+    let rbnd = if let Some(ct) = content_type {
+        let ct: mime::Mime = ct.parse().map_err(Error::batch)?;
+        if let Some(b) = ct.get_param("boundary") {
+            b.to_string()
+        } else {
+            return Err(Error::Batch("content type missing boundary".into()));
+        }
+    } else {
+        return Err(Error::Batch("content type missing from response".into()));
+    };
+    trace!(log, "boundary: {:#?}", rbnd);
+
+    let mp = match multipart_parse(x, rbnd.as_bytes()) {
+        Ok(mp) => mp,
+        Err(e) => {
+            let report = if x.len() < 200 { x } else { &x[..200] };
+            debug!(log, "response: {:#?}", String::from_utf8_lossy(report));
+            return Err(Error::Batch(format!(
+                "response multipart error: (boundary {:?}) {}",
+                rbnd, e
+            )));
+        }
+    };
+
+    let mut out: Vec<Option<BatchResponse>> =
+        requests.iter().map(|_| None).collect();
+    for p in &mp.parts {
+        let p = parse_part(log, &p.headers, &p.body, requests.len())?;
+        let i = p.index;
+        if out[i].is_some() {
+            return Err(Error::Batch(format!(
+                "duplicate response for request {} ({} {})",
+                i, requests[i].method, requests[i].path
+            )));
+        }
+        out[i] = Some(p);
+    }
+
+    out.into_iter()
+        .zip(requests)
+        .enumerate()
+        .map(|(i, (p, r))| {
+            p.ok_or_else(|| {
+                Error::Batch(format!(
+                    "no response for request {} ({} {})",
+                    i, r.method, r.path
+                ))
+            })
+        })
+        .collect()
+}
+
+fn parse_part(
+    log: &Logger,
+    headers: &std::collections::HashMap<String, String>,
+    body: &[u8],
+    count: usize,
+) -> Result<BatchResponse> {
+    let report = if body.len() < 200 { body } else { &body[..200] };
+    trace!(
+        log,
+        "process part: {:#?} {:#?}",
+        headers,
+        String::from_utf8_lossy(report)
+    );
+
+    if let Some(ct) = headers.get("content-type") {
+        let ct: mime::Mime = ct.parse().map_err(Error::batch)?;
+        match (ct.type_(), ct.subtype().as_str()) {
+            (mime::APPLICATION, "http") => (),
+            ct => {
+                return Err(Error::Batch(format!(
+                    "response part had wrong type: {:?}",
+                    ct
+                )))
+            }
+        };
+    } else {
+        return Err(Error::Batch(
+            "content type missing from response part".into(),
+        ));
+    }
+
+    let index: usize = if let Some(cid) = headers.get("content-id") {
+        match cid
+            .strip_prefix("response-req-")
+            .and_then(|n| n.parse::<usize>().ok())
+        {
+            Some(n) if n < count => n,
+            _ => {
+                return Err(Error::Batch(format!(
+                    "content id {:?} invalid in response part",
+                    cid
+                )));
+            }
+        }
+    } else {
+        return Err(Error::Batch(
+            "content id missing from response part".into(),
+        ));
+    };
+
+    let mut hdrs = [httparse::EMPTY_HEADER; 32];
+    let mut parser = httparse::Response::new(&mut hdrs);
+    let c = match parser.parse(body).map_err(Error::batch)? {
+        httparse::Status::Complete(c) => c,
+        httparse::Status::Partial => {
+            return Err(Error::Batch(
+                "response part response incomplete".into(),
+            ));
+        }
+    };
+    let status = parser
+        .code
+        .ok_or_else(|| Error::Batch("response part missing status".into()))?;
+
+    let mut content_type: Option<String> = None;
+    let mut content_length: Option<usize> = None;
+    let mut retry_after: Option<Duration> = None;
+    for h in parser.headers.iter() {
+        trace!(log, "part header: {:?}", h);
+        let value = || {
+            std::str::from_utf8(h.value)
+                .map(|v| v.trim().to_string())
+                .map_err(Error::batch)
+        };
+        if h.name.eq_ignore_ascii_case("content-type") {
+            content_type = Some(value()?);
+        } else if h.name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value()?.parse().map_err(Error::batch)?);
+        } else if h.name.eq_ignore_ascii_case("retry-after") {
+            retry_after = parse_retry_after(&value()?);
+        }
+    }
+
+    let body = &body[c..];
+    if let Some(cl) = content_length {
+        if cl != body.len() {
+            return Err(Error::Batch(format!(
+                "response part body len {} not what we expected (i.e., {})",
+                body.len(),
+                cl
+            )));
+        }
+    }
+
+    Ok(BatchResponse {
+        index,
+        status,
+        content_type,
+        retry_after,
+        body: body.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gmail::GMailBuilder;
+    use crate::testutil::logger;
+    use crate::token::StaticToken;
+
+    fn gmail() -> gmail::GMail {
+        GMailBuilder::new(logger(), StaticToken::new("token"))
+            .base_url("https://gmail.example.com/gmail/v1/")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn body_encoding() {
+        let requests = vec![
+            BatchRequest::get("users/me/messages/m0")
+                .query("format", "metadata")
+                .query("metadataHeaders", "Subject")
+                .query("metadataHeaders", "From"),
+            BatchRequest::get("users/me/messages")
+                .query("q", "from:a@b.com subject:\"x & y\" 100%/é=1"),
+            BatchRequest::post("users/me/messages/m1/modify")
+                .json(&serde_json::json!({ "addLabelIds": ["STARRED"] }))
+                .unwrap(),
+            BatchRequest::delete("users/me/messages/m2"),
+        ];
+
+        let body = request_body(&gmail(), &requests).unwrap();
+
+        let part = |n: usize, req: &str| {
+            format!(
+                "--{}\r\n\
+                Content-Type: application/http\r\n\
+                Content-ID: req-{}\r\n\
+                \r\n\
+                {}\r\n",
+                BOUNDARY, n, req
+            )
+        };
+        let expected = [
+            part(
+                0,
+                "GET /gmail/v1/users/me/messages/m0?format=metadata\
+                &metadataHeaders=Subject&metadataHeaders=From\r\n\r\n",
+            ),
+            part(
+                1,
+                "GET /gmail/v1/users/me/messages?q=from%3Aa%40b.com\
+                +subject%3A%22x+%26+y%22+100%25%2F%C3%A9%3D1\r\n\r\n",
+            ),
+            part(
+                2,
+                "POST /gmail/v1/users/me/messages/m1/modify\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 27\r\n\
+                \r\n\
+                {\"addLabelIds\":[\"STARRED\"]}",
+            ),
+            part(3, "DELETE /gmail/v1/users/me/messages/m2\r\n\r\n"),
+            format!("--{}--\r\n", BOUNDARY),
+        ]
+        .concat();
+
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+    }
+
+    const RB: &str = "batch_response";
+
+    /**
+     * Build one part of a batch response.  The headers, if any, should each
+     * end with CRLF.
+     */
+    fn part(cid: &str, status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "--{}\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: {}\r\n\
+            \r\n\
+            HTTP/1.1 {}\r\n\
+            {}\
+            Content-Length: {}\r\n\
+            \r\n\
+            {}\r\n",
+            RB,
+            cid,
+            status,
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    fn response(parts: &[String]) -> Vec<u8> {
+        format!("{}--{}--\r\n", parts.concat(), RB).into_bytes()
+    }
+
+    fn parse(body: &[u8], count: usize) -> Result<Vec<BatchResponse>> {
+        let requests = (0..count)
+            .map(|i| BatchRequest::get(format!("users/me/messages/m{}", i)))
+            .collect::<Vec<_>>();
+        let ct = format!("multipart/mixed; boundary={}", RB);
+
+        parse_response(&logger(), Some(&ct), body, &requests)
+    }
+
+    #[test]
+    fn parse_parts() {
+        let json = "Content-Type: application/json; charset=UTF-8\r\n";
+        let body = response(&[
+            part("response-req-0", "200 OK", json, r#"{"id": "m0"}"#),
+            part("response-req-1", "204 No Content", "", ""),
+        ]);
+
+        let res = parse(&body, 2).unwrap();
+        assert_eq!(res.len(), 2);
+
+        assert_eq!(res[0].index(), 0);
+        assert_eq!(res[0].status(), 200);
+        let v: serde_json::Value = res[0].json().unwrap();
+        assert_eq!(v["id"], "m0");
+
+        assert_eq!(res[1].status(), 204);
+        assert!(res[1].body().is_empty());
+        res[1].check().unwrap();
+    }
+
+    #[test]
+    fn parse_bad_parts() {
+        let ok = part("response-req-0", "200 OK", "", "");
+
+        /*
+         * The content ID must refer to one of our requests.
+         */
+        for cid in ["response-req-1", "req-0", "response-req-x"] {
+            let body = response(&[part(cid, "200 OK", "", "")]);
+            assert!(matches!(parse(&body, 1), Err(Error::Batch(_))));
+        }
+
+        /*
+         * The length of the body must match the Content-Length header.
+         */
+        let body =
+            response(&[ok.replace("Content-Length: 0", "Content-Length: 4")]);
+        assert!(matches!(parse(&body, 1), Err(Error::Batch(_))));
+
+        /*
+         * The response must be a multipart response with a boundary.
+         */
+        let body = response(&[ok]);
+        let requests = [BatchRequest::get("users/me/profile")];
+        for ct in [None, Some("multipart/mixed"), Some("application/json")] {
+            assert!(matches!(
+                parse_response(&logger(), ct, &body, &requests),
+                Err(Error::Batch(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_out_of_order() {
+        let json = "Content-Type: application/json; charset=UTF-8\r\n";
+        let body = response(&[
+            part("response-req-2", "200 OK", json, r#"{"id": "m2"}"#),
+            part(
+                "response-req-0",
+                "404 Not Found",
+                json,
+                r#"{"error": {"code": 404, "message": "Not Found"}}"#,
+            ),
+            part(
+                "response-req-1",
+                "429 Too Many Requests",
+                "Retry-After: 7\r\n",
+                "",
+            ),
+        ]);
+
+        let res = parse(&body, 3).unwrap();
+        assert_eq!(
+            res.iter().map(BatchResponse::index).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+
+        assert!(matches!(res[0].error(), Some(Error::NotFound { .. })));
+        assert!(matches!(
+            res[1].error(),
+            Some(Error::RateLimited {
+                status: 429,
+                retry_after: Some(d),
+                ..
+            }) if d == Duration::from_secs(7)
+        ));
+        let v: serde_json::Value = res[2].json().unwrap();
+        assert_eq!(v["id"], "m2");
+    }
+
+    #[test]
+    fn parse_duplicate_or_missing() {
+        let p = |n: usize| {
+            part(&format!("response-req-{}", n), "204 No Content", "", "")
+        };
+
+        match parse(&response(&[p(1), p(0), p(2), p(0)]), 3) {
+            Err(Error::Batch(m)) => assert_eq!(
+                m,
+                "duplicate response for request 0 (GET users/me/messages/m0)"
+            ),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        match parse(&response(&[p(2), p(0)]), 3) {
+            Err(Error::Batch(m)) => assert_eq!(
+                m,
+                "no response for request 1 (GET users/me/messages/m1)"
+            ),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        assert_eq!(parse(&response(&[p(1), p(2), p(0)]), 3).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,109 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use serde::Deserialize;
+use slog::debug;
+use std::sync::Arc;
+
+use super::error::{json, Result};
+use super::gmail::{self, Draft};
+use super::paging::{Page, Pages};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RDrafts {
+    #[serde(default)]
+    drafts: Vec<Draft>,
+    next_page_token: Option<String>,
+    #[serde(default)]
+    result_size_estimate: u64,
+}
+
+pub struct DraftsConfig {
+    parent: Arc<gmail::GMailInner>,
+    perpage: Option<u32>,
+    q: Option<String>,
+    spamtrash: bool,
+    resume_from_token: Option<String>,
+}
+
+impl DraftsConfig {
+    pub(crate) fn new(parent: &gmail::GMail) -> DraftsConfig {
+        DraftsConfig {
+            parent: Arc::clone(&parent.0),
+            perpage: None,
+            q: None,
+            spamtrash: false,
+            resume_from_token: None,
+        }
+    }
+
+    pub fn query<S: AsRef<str>>(mut self, s: S) -> DraftsConfig {
+        self.q = Some(s.as_ref().to_string());
+        self
+    }
+
+    pub fn include_spam_trash(mut self, i: bool) -> DraftsConfig {
+        self.spamtrash = i;
+        self
+    }
+
+    pub fn resume_from_token(mut self, s: &str) -> DraftsConfig {
+        self.resume_from_token = Some(s.to_string());
+        self
+    }
+
+    pub fn batch_size(mut self, n: u32) -> DraftsConfig {
+        self.perpage = Some(n);
+        self
+    }
+
+    pub fn start(self) -> Drafts {
+        let log = self.parent.log.clone();
+        let page_token = self.resume_from_token.clone();
+        let c = Arc::new(self);
+
+        Pages::new(log, "drafts", page_token, move |pt| {
+            fetch_page(Arc::clone(&c), pt)
+        })
+    }
+}
+
+pub type Drafts = Pages<Draft>;
+
+async fn fetch_page(
+    c: Arc<DraftsConfig>,
+    page_token: Option<String>,
+) -> Result<Page<Draft>> {
+    let log = &c.parent.log;
+
+    let url = c.parent.url("users/me/drafts");
+
+    let res = c
+        .parent
+        .execute(|| {
+            let mut req = c.parent.client.get(&url);
+
+            if let Some(q) = &c.q {
+                req = req.query(&[("q", q)]);
+            }
+            if c.spamtrash {
+                req = req.query(&[("includeSpamTrash", "true")]);
+            }
+            if let Some(pt) = &page_token {
+                req = req.query(&[("pageToken", pt)]);
+            }
+            if let Some(pp) = &c.perpage {
+                req = req.query(&[("maxResults", pp.to_string())]);
+            }
+
+            req
+        })
+        .await?;
+
+    let o: RDrafts = json(res).await?;
+    debug!(log, "result count estimate: {}", o.result_size_estimate);
+
+    Ok((o.drafts, o.next_page_token))
+}
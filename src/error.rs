@@ -0,0 +1,305 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::{self, HeaderMap};
+use serde::Deserialize;
+
+use super::gauth::AuthError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/**
+ * One of the detailed errors within an error response from a Google API.
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct GoogleErrorDetail {
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default)]
+    pub reason: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+/**
+ * The body of an error response from a Google API.
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct GoogleError {
+    #[serde(default)]
+    pub errors: Vec<GoogleErrorDetail>,
+    pub code: u32,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl GoogleError {
+    /**
+     * Parse an error response body, if it is the kind we expect.
+     */
+    pub(crate) fn parse(body: &[u8]) -> Option<GoogleError> {
+        #[derive(Deserialize)]
+        struct E {
+            error: GoogleError,
+        }
+
+        serde_json::from_slice::<E>(body).ok().map(|e| e.error)
+    }
+
+    /**
+     * Google report per-user and per-project rate limiting with a 403
+     * status, rather than a 429, and a reason in the "usageLimits" domain.
+     */
+    pub fn is_rate_limit(&self) -> bool {
+        self.errors.iter().any(|e| {
+            e.domain == "usageLimits"
+                && (e.reason == "userRateLimitExceeded"
+                    || e.reason == "rateLimitExceeded")
+        })
+    }
+
+    pub fn reasons(&self) -> Vec<&str> {
+        self.errors.iter().map(|e| e.reason.as_str()).collect()
+    }
+}
+
+fn describe(status: u16, body: &Option<GoogleError>) -> String {
+    if let Some(ge) = body {
+        format!("{} {}", status, ge.message)
+    } else {
+        status.to_string()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /**
+     * The requested resource (e.g., a message or label) does not exist.
+     */
+    #[error("not found: {}", describe(404, body))]
+    NotFound { body: Option<GoogleError> },
+    /**
+     * The request was rejected because of a rate limit or quota, either with
+     * a 429 status, or with a 403 status and a "usageLimits" reason.
+     */
+    #[error("rate limited: {}", describe(*status, body))]
+    RateLimited {
+        status: u16,
+        retry_after: Option<Duration>,
+        body: Option<GoogleError>,
+    },
+    /**
+     * The API rejected our access token.
+     */
+    #[error("unauthorised: {}", describe(401, body))]
+    Unauthorized { body: Option<GoogleError> },
+    /**
+     * We were unable to obtain an access token.
+     */
+    #[error("authorisation: {0}")]
+    Auth(#[from] AuthError),
+    /**
+     * Some other unsuccessful HTTP status.
+     */
+    #[error("HTTP error: {}", describe(*status, body))]
+    Http {
+        status: u16,
+        body: Option<GoogleError>,
+    },
+    /**
+     * A response body could not be decoded.
+     */
+    #[error("decoding response: {0}")]
+    Decode(String),
+    /**
+     * A batch response did not conform to the batch protocol.
+     */
+    #[error("batch protocol error: {0}")]
+    Batch(String),
+    /**
+     * The request could not be sent, or the response could not be received.
+     */
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    /**
+     * Output could not be written; e.g., while saving an attachment.
+     */
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /**
+     * An argument was not valid; e.g., a malformed base URL given to the
+     * builder, or an attachment file name without a final component.
+     */
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    /**
+     * Some other failure; e.g., from a TokenSource implemented outside this
+     * crate.
+     */
+    #[error("{0}")]
+    Other(String),
+}
+
+impl Error {
+    /**
+     * Construct an error from an unsuccessful HTTP status and response body.
+     */
+    pub(crate) fn from_status(
+        status: u16,
+        retry_after: Option<Duration>,
+        body: &[u8],
+    ) -> Error {
+        let body = GoogleError::parse(body);
+
+        match status {
+            401 => Error::Unauthorized { body },
+            404 => Error::NotFound { body },
+            429 => Error::RateLimited {
+                status,
+                retry_after,
+                body,
+            },
+            403 if body
+                .as_ref()
+                .map(|b| b.is_rate_limit())
+                .unwrap_or(false) =>
+            {
+                Error::RateLimited {
+                    status,
+                    retry_after,
+                    body,
+                }
+            }
+            _ => Error::Http { status, body },
+        }
+    }
+
+    pub(crate) fn batch<E: std::fmt::Display>(e: E) -> Error {
+        Error::Batch(e.to_string())
+    }
+
+    pub(crate) fn decode<E: std::fmt::Display>(e: E, body: &[u8]) -> Error {
+        let report = if body.len() < 200 { body } else { &body[..200] };
+        Error::Decode(format!("{}: {}", e, String::from_utf8_lossy(report)))
+    }
+
+    /**
+     * The HTTP status of the failed request, if there was one.
+     */
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::NotFound { .. } => Some(404),
+            Error::Unauthorized { .. } => Some(401),
+            Error::RateLimited { status, .. } | Error::Http { status, .. } => {
+                Some(*status)
+            }
+            Error::Request(e) => e.status().map(|s| s.as_u16()),
+            _ => None,
+        }
+    }
+
+    /**
+     * The error body returned by the API, if there was one.
+     */
+    pub fn google_error(&self) -> Option<&GoogleError> {
+        match self {
+            Error::NotFound { body }
+            | Error::Unauthorized { body }
+            | Error::RateLimited { body, .. }
+            | Error::Http { body, .. } => body.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::NotFound { .. })
+    }
+
+    pub fn is_rate_limit(&self) -> bool {
+        matches!(self, Error::RateLimited { .. })
+    }
+}
+
+/**
+ * Interpret the value of a Retry-After header, which is either a number of
+ * seconds or an HTTP date.  If the date has already passed, we may try again
+ * straight away.
+ */
+pub(crate) fn parse_retry_after(v: &str) -> Option<Duration> {
+    let v = v.trim();
+    if let Ok(secs) = v.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let when = httpdate::parse_http_date(v).ok()?;
+    Some(
+        when.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+/**
+ * Check the status of a response, turning it into an error if it was not
+ * successful.
+ */
+pub(crate) async fn check(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let ra = retry_after(res.headers());
+    let body = res.bytes().await?;
+    Err(Error::from_status(status.as_u16(), ra, &body))
+}
+
+/**
+ * Check the status of a response, and then decode the JSON body.
+ */
+pub(crate) async fn json<T>(res: reqwest::Response) -> Result<T>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let body = check(res).await?.bytes().await?;
+    serde_json::from_slice(&body).map_err(|e| Error::decode(e, &body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_values() {
+        assert_eq!(parse_retry_after("7"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-1"), None);
+
+        /*
+         * A date in the past means there is no need to wait.
+         */
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+
+        let when = SystemTime::now() + Duration::from_secs(120);
+        let d = parse_retry_after(&httpdate::fmt_http_date(when)).unwrap();
+        assert!(d > Duration::from_secs(110) && d <= Duration::from_secs(120));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(header::RETRY_AFTER, "30".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(30)));
+    }
+}
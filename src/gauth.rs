@@ -3,19 +3,35 @@
  * Copyright 2022 Oxide Computer Company
  */
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
+use futures_util::future::{BoxFuture, FutureExt, WeakShared};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use reqwest::redirect;
 use reqwest::StatusCode;
 use reqwest::{Client, ClientBuilder};
 
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
-use slog::{debug, Logger};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
 
-use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use slog::{debug, info, warn, Logger};
+
+use super::error::retry_after;
+use super::scope::Scope;
+use super::token::{StoredToken, TokenStore};
 
 #[allow(dead_code)]
 #[derive(Deserialize)]
@@ -23,6 +39,11 @@ struct RExchange {
     access_token: String,
     expires_in: u64,
     refresh_token: String,
+    /*
+     * The server may omit the scope if it is the same as the scope that was
+     * requested (RFC 6749, section 5.1).
+     */
+    #[serde(default)]
     scope: String,
     token_type: String,
 }
@@ -31,24 +52,358 @@ struct RExchange {
 #[derive(Deserialize)]
 struct RRefresh {
     access_token: String,
+    /*
+     * The server may choose to rotate the refresh token, in which case we
+     * must use the new one from now on.
+     */
+    refresh_token: Option<String>,
+    /*
+     * The response to a JWT bearer assertion does not include the scope.
+     */
+    #[serde(default)]
     scope: String,
     token_type: String,
     expires_in: u64,
 }
 
+/**
+ * The method used to derive a PKCE (RFC 7636) code challenge from the code
+ * verifier.  S256 should be used unless the authorisation server does not
+ * support it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PkceMethod {
+    S256,
+    Plain,
+}
+
+impl PkceMethod {
+    fn name(&self) -> &'static str {
+        match self {
+            PkceMethod::S256 => "S256",
+            PkceMethod::Plain => "plain",
+        }
+    }
+
+    fn challenge(&self, verifier: &str) -> String {
+        match self {
+            PkceMethod::S256 => base64::encode_config(
+                Sha256::digest(verifier.as_bytes()),
+                base64::URL_SAFE_NO_PAD,
+            ),
+            PkceMethod::Plain => verifier.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ROAuthError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/**
+ * Errors from interactions with the OAuth authorisation server.
+ */
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum AuthError {
+    /**
+     * The authorisation code or refresh token is invalid, has expired, or has
+     * been revoked.  The user must authorise us again.
+     */
+    #[error("invalid grant: {0}")]
+    InvalidGrant(String),
+    /**
+     * We have no refresh token, so the user must authorise us.
+     */
+    #[error("no refresh token; authorisation required")]
+    NoRefreshToken,
+    /**
+     * The client ID or secret were rejected, or the client is not permitted
+     * to use this type of grant.
+     */
+    #[error("invalid client: {0}")]
+    InvalidClient(String),
+    /**
+     * The authorisation server asked us to slow down.  If it said how long
+     * to wait before trying again, in a Retry-After header, that period is
+     * included.
+     */
+    #[error("rate limited by authorisation server")]
+    RateLimited { retry_after: Option<Duration> },
+    /**
+     * The authorisation server had a problem that may go away if we try
+     * again later.
+     */
+    #[error("authorisation server error {status}: {message}")]
+    Transient { status: u16, message: String },
+    /**
+     * Some other error reported by the authorisation server.
+     */
+    #[error("OAuth error {error} ({status}): {description}")]
+    OAuth {
+        status: u16,
+        error: String,
+        description: String,
+    },
+    /**
+     * The authorisation server sent the browser back to our loopback
+     * listener with an error rather than a code; e.g., "access_denied" if
+     * the user declined.
+     */
+    #[error("authorisation failed: {error}: {description}")]
+    Redirect { error: String, description: String },
+    /**
+     * The loopback listener could not accept a connection from the browser.
+     */
+    #[error("loopback listener: {0}")]
+    Listener(String),
+    /**
+     * We could not make a request to the authorisation server, or could not
+     * read the response.  Only a failure to connect or a timeout is
+     * considered transient.
+     */
+    #[error("request to authorisation server failed: {0}")]
+    Request(#[source] Arc<reqwest::Error>),
+    /**
+     * The TokenStore failed to load, save or clear our tokens.
+     */
+    #[error("could not store tokens: {0}")]
+    Store(String),
+    /**
+     * The client configuration or service account key is not valid.
+     */
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /**
+     * Some other failure, such as a call that is not valid for this kind of
+     * GAuth, or a response from the authorisation server that is not in the
+     * expected form.
+     */
+    #[error("{0}")]
+    Other(String),
+}
+
+impl AuthError {
+    /**
+     * Does the user need to authorise us again before we can make progress?
+     */
+    pub fn needs_reconsent(&self) -> bool {
+        matches!(self, AuthError::InvalidGrant(_) | AuthError::NoRefreshToken)
+    }
+
+    /**
+     * Might the same request succeed if we try again later?
+     */
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AuthError::RateLimited { .. } | AuthError::Transient { .. } => true,
+            /*
+             * A request that could not be built, or a response we could not
+             * make sense of, will fail the same way next time.
+             */
+            AuthError::Request(e) => e.is_connect() || e.is_timeout(),
+            _ => false,
+        }
+    }
+
+    fn request(e: reqwest::Error) -> AuthError {
+        AuthError::Request(Arc::new(e))
+    }
+
+    fn from_response(
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        body: &[u8],
+    ) -> AuthError {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return AuthError::RateLimited { retry_after };
+        }
+
+        let text = String::from_utf8_lossy(body).to_string();
+
+        if status.is_server_error() {
+            return AuthError::Transient {
+                status: status.as_u16(),
+                message: text,
+            };
+        }
+
+        match serde_json::from_slice::<ROAuthError>(body) {
+            Ok(e) => {
+                let description = e.error_description.unwrap_or_default();
+                match e.error.as_str() {
+                    "invalid_grant" => AuthError::InvalidGrant(description),
+                    "invalid_client" | "unauthorized_client" => {
+                        AuthError::InvalidClient(description)
+                    }
+                    "rate_limit_exceeded" => {
+                        AuthError::RateLimited { retry_after }
+                    }
+                    _ => AuthError::OAuth {
+                        status: status.as_u16(),
+                        error: e.error,
+                        description,
+                    },
+                }
+            }
+            Err(_) => AuthError::Other(format!(
+                "unexpected response {}: {}",
+                status, text
+            )),
+        }
+    }
+}
+
+type RefreshFuture = BoxFuture<'static, Result<(), AuthError>>;
+
 #[derive(Clone)]
 struct GAuthInner {
     refresh_token: String,
     access_token: String,
     expiry: Option<SystemTime>,
+    pkce: Option<PkceMethod>,
+    pkce_verifier: Option<String>,
+    /*
+     * The scopes in the URL from auth_token(), which we assume were granted
+     * if the server does not list them when we exchange the code.
+     */
+    requested_scopes: BTreeSet<Scope>,
+    /*
+     * The in-flight refresh, if there is one.  The refresh future holds a
+     * clone of the GAuth, so only the tasks waiting on it may hold it
+     * strongly; otherwise, if they all went away before it completed, the
+     * future and this state would keep each other alive forever.
+     */
+    refreshing: Option<WeakShared<RefreshFuture>>,
+    scopes: BTreeSet<Scope>,
+    /*
+     * Incremented each time our tokens are revoked, so that a refresh that
+     * was in flight at the time does not bring them back.
+     */
+    generation: u64,
+}
+
+impl GAuthInner {
+    fn new() -> GAuthInner {
+        GAuthInner {
+            refresh_token: String::from(""),
+            access_token: String::from(""),
+            expiry: None,
+            pkce: None,
+            pkce_verifier: None,
+            requested_scopes: BTreeSet::new(),
+            refreshing: None,
+            scopes: BTreeSet::new(),
+            generation: 0,
+        }
+    }
+}
+
+/**
+ * Options for an interactive authorisation request.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct AuthOptions {
+    scopes: BTreeSet<Scope>,
+    include_granted_scopes: bool,
+    offline: bool,
+    prompt_consent: bool,
+    login_hint: Option<String>,
+}
+
+impl AuthOptions {
+    pub fn new() -> AuthOptions {
+        Default::default()
+    }
+
+    /**
+     * The scopes requested by auth_token(): the user profile, and either
+     * read-only or modify access to Gmail.
+     */
+    pub fn basic(readonly: bool) -> AuthOptions {
+        AuthOptions::new().scope(Scope::Profile).scope(if readonly {
+            Scope::GmailReadonly
+        } else {
+            Scope::GmailModify
+        })
+    }
+
+    pub fn scope(mut self, scope: Scope) -> AuthOptions {
+        self.scopes.insert(scope);
+        self
+    }
+
+    pub fn scopes<I: IntoIterator<Item = Scope>>(
+        mut self,
+        scopes: I,
+    ) -> AuthOptions {
+        self.scopes.extend(scopes);
+        self
+    }
+
+    /**
+     * Ask for the scopes the user has already granted to this client to be
+     * included in the new grant, so that scopes can be requested
+     * incrementally.
+     */
+    pub fn include_granted_scopes(mut self, i: bool) -> AuthOptions {
+        self.include_granted_scopes = i;
+        self
+    }
+
+    /**
+     * Request a refresh token, so that we may act while the user is not
+     * present.
+     */
+    pub fn offline(mut self, o: bool) -> AuthOptions {
+        self.offline = o;
+        self
+    }
+
+    /**
+     * Always show the consent screen, even if the user has already consented;
+     * Google will only issue a new refresh token for an existing grant when
+     * this is set.
+     */
+    pub fn prompt_consent(mut self, p: bool) -> AuthOptions {
+        self.prompt_consent = p;
+        self
+    }
+
+    pub fn login_hint<S: AsRef<str>>(mut self, email: S) -> AuthOptions {
+        self.login_hint = Some(email.as_ref().to_string());
+        self
+    }
+
+    fn scope_string(&self) -> String {
+        self.scopes
+            .iter()
+            .map(Scope::as_str)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigInstalled {
     client_id: String,
+    /*
+     * When PKCE is in use, the client secret is not strictly required by all
+     * authorisation servers, so we allow it to be omitted.
+     */
+    #[serde(default)]
     client_secret: String,
     auth_uri: String,
     token_uri: String,
+    /*
+     * The client_id.json file from Google does not include the revocation
+     * endpoint, but it may be specified; e.g., for testing.
+     */
+    #[serde(default = "default_revoke_uri")]
+    revoke_uri: String,
+    #[serde(default = "default_device_auth_uri")]
+    device_auth_uri: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,6 +411,166 @@ pub struct Config {
     installed: ConfigInstalled,
 }
 
+/**
+ * The JSON key file for a service account, as downloaded from the Google Cloud
+ * console.
+ */
+#[derive(Deserialize)]
+pub struct ServiceAccountKey {
+    #[serde(rename = "type")]
+    typ: String,
+    #[serde(default)]
+    client_id: String,
+    client_email: String,
+    private_key_id: Option<String>,
+    private_key: String,
+    #[serde(default = "default_auth_uri")]
+    auth_uri: String,
+    token_uri: String,
+    #[serde(default = "default_revoke_uri")]
+    revoke_uri: String,
+}
+
+/*
+ * The private key must not end up in logs, so it is left out of the Debug
+ * output.
+ */
+impl fmt::Debug for ServiceAccountKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServiceAccountKey")
+            .field("typ", &self.typ)
+            .field("client_id", &self.client_id)
+            .field("client_email", &self.client_email)
+            .field("private_key_id", &self.private_key_id)
+            .field("private_key", &"<redacted>")
+            .field("auth_uri", &self.auth_uri)
+            .field("token_uri", &self.token_uri)
+            .field("revoke_uri", &self.revoke_uri)
+            .finish()
+    }
+}
+
+fn default_auth_uri() -> String {
+    "https://accounts.google.com/o/oauth2/auth".to_string()
+}
+
+fn default_revoke_uri() -> String {
+    "https://oauth2.googleapis.com/revoke".to_string()
+}
+
+fn default_device_auth_uri() -> String {
+    "https://oauth2.googleapis.com/device/code".to_string()
+}
+
+const DEVICE_CODE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Deserialize)]
+struct RDeviceCode {
+    device_code: String,
+    user_code: String,
+    /*
+     * Google use "verification_url", rather than the "verification_uri" from
+     * RFC 8628.
+     */
+    #[serde(alias = "verification_uri")]
+    verification_url: String,
+    expires_in: u64,
+    #[serde(default = "default_device_interval")]
+    interval: u64,
+}
+
+fn default_device_interval() -> u64 {
+    5
+}
+
+/**
+ * RFC 8628 requires that we increase the polling interval by five seconds
+ * each time we are asked to slow down.
+ */
+const DEVICE_SLOW_DOWN: Duration = Duration::from_secs(5);
+
+/**
+ * An in-progress device authorisation, as started by GAuth::device_start().
+ * The user must visit the verification URL on some other device and enter the
+ * user code, while we poll for the result with GAuth::device_poll().
+ */
+#[derive(Debug, Clone)]
+pub struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    verification_url: String,
+    expires_at: SystemTime,
+    interval: Duration,
+    slow_down: Duration,
+    scopes: BTreeSet<Scope>,
+}
+
+impl DeviceCode {
+    pub fn user_code(&self) -> &str {
+        &self.user_code
+    }
+
+    pub fn verification_url(&self) -> &str {
+        &self.verification_url
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+}
+
+const JWT_BEARER: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+struct ServiceAccount {
+    client_email: String,
+    key: EncodingKey,
+    key_id: Option<String>,
+    subject: Option<String>,
+    scope: String,
+}
+
+impl ServiceAccount {
+    /**
+     * Build and sign a JWT assertion that we can exchange at the token URI
+     * for an access token.  If we have a subject, the token will allow us to
+     * act as that user through domain-wide delegation.
+     */
+    fn assertion(&self, aud: &str) -> Result<String, AuthError> {
+        #[derive(Serialize)]
+        struct Claims<'a> {
+            iss: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            sub: Option<&'a str>,
+            scope: &'a str,
+            aud: &'a str,
+            iat: u64,
+            exp: u64,
+        }
+
+        let iat = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| AuthError::Other(format!("system time: {}", e)))?
+            .as_secs();
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = self.key_id.clone();
+
+        jsonwebtoken::encode(
+            &header,
+            &Claims {
+                iss: &self.client_email,
+                sub: self.subject.as_deref(),
+                scope: &self.scope,
+                aud,
+                iat,
+                exp: iat + 3600,
+            },
+            &self.key,
+        )
+        .map_err(|e| AuthError::Other(format!("signing assertion: {}", e)))
+    }
+}
+
 #[derive(Clone)]
 pub struct GAuth {
     log: Logger,
@@ -64,19 +579,24 @@ pub struct GAuth {
     client_secret: String,
     auth_uri: reqwest::Url,
     token_uri: reqwest::Url,
+    revoke_uri: reqwest::Url,
+    device_auth_uri: reqwest::Url,
     redirect_uri: String,
+    service_account: Option<Arc<ServiceAccount>>,
+    store: Option<Arc<dyn TokenStore>>,
+    store_lock: Arc<Mutex<()>>,
 
     client: Client,
     inner: Arc<Mutex<GAuthInner>>,
 }
 
 impl GAuth {
-    pub fn new(log: Logger, config: Config) -> Result<GAuth> {
+    pub fn new(log: Logger, config: Config) -> Result<GAuth, AuthError> {
         /*
          * Even though, in its continuing war on users, Google have recklessly
          * deprecated the OOB redirect URI, we should not change the default
-         * behaviour without a major roll -- especially when it is not clear
-         * what the replacement would even be.
+         * behaviour without a major roll.  Consumers that need a working
+         * interactive flow should use loopback() instead.
          */
         Self::new_with_redirect_uri(log, config, "urn:ietf:wg:oauth:2.0:oob")
     }
@@ -85,28 +605,193 @@ impl GAuth {
         log: Logger,
         config: Config,
         redirect_uri: &str,
-    ) -> Result<GAuth> {
-        let cb = ClientBuilder::new()
-            .tcp_keepalive(Duration::from_secs(30))
-            .connect_timeout(Duration::from_secs(30))
-            .redirect(redirect::Policy::none());
-
+    ) -> Result<GAuth, AuthError> {
         Ok(GAuth {
             log,
-            client: cb.build().expect("build client"),
+            client: build_client(),
 
             client_id: config.installed.client_id,
             client_secret: config.installed.client_secret,
             auth_uri: reqvalurl(&config.installed.auth_uri, "auth_uri")?,
             token_uri: reqvalurl(&config.installed.token_uri, "token_uri")?,
+            revoke_uri: reqvalurl(&config.installed.revoke_uri, "revoke_uri")?,
+            device_auth_uri: reqvalurl(
+                &config.installed.device_auth_uri,
+                "device_auth_uri",
+            )?,
             redirect_uri: redirect_uri.to_string(),
+            service_account: None,
+            store: None,
+            store_lock: Arc::new(Mutex::new(())),
+
+            inner: Arc::new(Mutex::new(GAuthInner::new())),
+        })
+    }
+
+    /**
+     * Authenticate as a service account, rather than as a user of an
+     * installed application.  If a subject email address is provided, the
+     * service account will impersonate that user; this requires domain-wide
+     * delegation to have been configured for the service account in the
+     * Workspace admin console.
+     */
+    pub fn new_service_account(
+        log: Logger,
+        key: ServiceAccountKey,
+        subject: Option<&str>,
+        scopes: &[Scope],
+    ) -> Result<GAuth, AuthError> {
+        if key.typ != "service_account" {
+            return Err(AuthError::Config(format!(
+                "key file has type {:?}, not a service account",
+                key.typ
+            )));
+        }
+        if scopes.is_empty() {
+            return Err(AuthError::Config(
+                "at least one scope is required".into(),
+            ));
+        }
+
+        let scope = scopes
+            .iter()
+            .map(Scope::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let sa = ServiceAccount {
+            client_email: key.client_email,
+            key: EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+                .map_err(|e| {
+                    AuthError::Config(format!(
+                        "service account private key: {}",
+                        e
+                    ))
+                })?,
+            key_id: key.private_key_id,
+            subject: subject.map(str::to_string),
+            scope,
+        };
+
+        Ok(GAuth {
+            log,
+            client: build_client(),
+
+            client_id: key.client_id,
+            client_secret: String::new(),
+            auth_uri: reqvalurl(&key.auth_uri, "auth_uri")?,
+            token_uri: reqvalurl(&key.token_uri, "token_uri")?,
+            revoke_uri: reqvalurl(&key.revoke_uri, "revoke_uri")?,
+            device_auth_uri: reqvalurl(
+                default_device_auth_uri(),
+                "device_auth_uri",
+            )?,
+            redirect_uri: String::new(),
+            service_account: Some(Arc::new(sa)),
+            store: None,
+            store_lock: Arc::new(Mutex::new(())),
+
+            inner: Arc::new(Mutex::new(GAuthInner::new())),
+        })
+    }
+
+    /**
+     * Use a token store to persist authentication state.  Any state already
+     * in the store is loaded now, and the store will be updated whenever
+     * exchange() or refresh() obtain new tokens.
+     */
+    pub fn with_token_store<T: TokenStore + 'static>(
+        mut self,
+        store: T,
+    ) -> Result<GAuth, AuthError> {
+        if let Some(st) =
+            store.load().map_err(|e| AuthError::Store(e.to_string()))?
+        {
+            debug!(self.log, "loaded stored token state");
+
+            let mut i = self.inner.lock().unwrap();
+            i.refresh_token = st.refresh_token;
+            i.access_token = st.access_token;
+            i.expiry = st
+                .expiry
+                .map(|s| SystemTime::UNIX_EPOCH + Duration::from_secs(s));
+            i.scopes = Scope::parse_list(&st.scope);
+        }
+
+        self.store = Some(Arc::new(store));
+        Ok(self)
+    }
+
+    /**
+     * Save our tokens in the token store, if we have one.  If they have been
+     * revoked since the specified generation, there is nothing to save.
+     */
+    async fn persist(&self, generation: u64) -> Result<(), AuthError> {
+        if self.store.is_some() {
+            let st = {
+                let i = self.inner.lock().unwrap();
+                if i.generation != generation {
+                    return Ok(());
+                }
+                StoredToken {
+                    refresh_token: i.refresh_token.clone(),
+                    access_token: i.access_token.clone(),
+                    expiry: i.expiry.and_then(|et| {
+                        et.duration_since(SystemTime::UNIX_EPOCH)
+                            .ok()
+                            .map(|d| d.as_secs())
+                    }),
+                    scope: i
+                        .scopes
+                        .iter()
+                        .map(Scope::as_str)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                }
+            };
+
+            self.with_store(Some(generation), move |s| s.save(&st))
+                .await?;
+        }
 
-            inner: Arc::new(Mutex::new(GAuthInner {
-                refresh_token: String::from(""),
-                access_token: String::from(""),
-                expiry: None,
-            })),
+        Ok(())
+    }
+
+    /**
+     * Token stores may do blocking I/O, so run store operations on a thread
+     * where that will not hold up other tasks; e.g., those waiting on a shared
+     * refresh.  Operations are made one at a time.  If a generation is
+     * specified, the operation is skipped if our tokens have been revoked
+     * since then, so that a save cannot follow the clear.
+     */
+    async fn with_store<F>(
+        &self,
+        generation: Option<u64>,
+        f: F,
+    ) -> Result<(), AuthError>
+    where
+        F: FnOnce(&dyn TokenStore) -> std::io::Result<()> + Send + 'static,
+    {
+        let store = if let Some(store) = &self.store {
+            Arc::clone(store)
+        } else {
+            return Ok(());
+        };
+        let lock = Arc::clone(&self.store_lock);
+        let inner = Arc::clone(&self.inner);
+
+        tokio::task::spawn_blocking(move || {
+            let _lock = lock.lock().unwrap();
+            if let Some(generation) = generation {
+                if inner.lock().unwrap().generation != generation {
+                    return Ok(());
+                }
+            }
+            f(store.as_ref())
         })
+        .await
+        .map_err(|e| AuthError::Store(e.to_string()))?
+        .map_err(|e| AuthError::Store(e.to_string()))
     }
 
     pub fn access_token(&self) -> String {
@@ -121,23 +806,124 @@ impl GAuth {
         self.inner.lock().unwrap().refresh_token = String::from(rt);
     }
 
+    /**
+     * The scopes most recently reported as granted by the authorisation
+     * server.  This set is empty until we have obtained a token.
+     */
+    pub fn granted_scopes(&self) -> BTreeSet<Scope> {
+        self.inner.lock().unwrap().scopes.clone()
+    }
+
+    /**
+     * Do our granted scopes allow the access that this scope would allow?
+     */
+    pub fn has_scope(&self, scope: &Scope) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .scopes
+            .iter()
+            .any(|s| s.implies(scope))
+    }
+
+    /**
+     * Enable (or, with None, disable) the use of PKCE for subsequent
+     * authorisation requests.  When enabled, each authorisation URL includes a
+     * fresh code challenge.  The code verifier for the URL from auth_token()
+     * is retained here until it is sent with the next exchange(), while each
+     * Loopback keeps the verifier for its own URL.
+     */
+    pub fn set_pkce(&self, method: Option<PkceMethod>) {
+        let mut i = self.inner.lock().unwrap();
+        i.pkce = method;
+        i.pkce_verifier = None;
+    }
+
     /**
      * Build a URL to give to the user, so that they can open it in their
      * browser and get an authentication code.  That code should then be passed
      * to exchange().
      */
-    pub fn auth_token(&self, readonly: bool) -> Result<String> {
+    pub fn auth_token(&self, readonly: bool) -> Result<String, AuthError> {
+        self.auth_token_with(&AuthOptions::basic(readonly))
+    }
+
+    /**
+     * Build a URL as per auth_token(), but with control over the scopes and
+     * other options in the request.
+     */
+    pub fn auth_token_with(
+        &self,
+        opts: &AuthOptions,
+    ) -> Result<String, AuthError> {
+        let (url, verifier) = self.auth_url(opts, &self.redirect_uri, None)?;
+
+        /*
+         * The verifier replaces any from a prior call, as only the most
+         * recent URL given to the user can be used to complete an exchange.
+         */
+        let mut i = self.inner.lock().unwrap();
+        i.pkce_verifier = verifier;
+        i.requested_scopes = opts.scopes.clone();
+        Ok(url)
+    }
+
+    /**
+     * Build an authorisation URL.  If PKCE is enabled, a new code verifier is
+     * generated for the request and returned with the URL; the caller must
+     * keep it to send with the exchange.
+     */
+    fn auth_url(
+        &self,
+        opts: &AuthOptions,
+        redirect_uri: &str,
+        state: Option<&str>,
+    ) -> Result<(String, Option<String>), AuthError> {
+        if self.service_account.is_some() {
+            return Err(AuthError::Other(
+                "service accounts do not use interactive authorisation".into(),
+            ));
+        }
+        if opts.scopes.is_empty() {
+            return Err(AuthError::Other(
+                "at least one scope is required".into(),
+            ));
+        }
+
         let mut params: HashMap<&str, &str> = HashMap::new();
         params.insert("client_id", &self.client_id);
-        params.insert("redirect_uri", &self.redirect_uri);
+        params.insert("redirect_uri", redirect_uri);
         params.insert("response_type", "code");
+        if let Some(state) = state {
+            params.insert("state", state);
+        }
+        if opts.include_granted_scopes {
+            params.insert("include_granted_scopes", "true");
+        }
+        if opts.offline {
+            params.insert("access_type", "offline");
+        }
+        if opts.prompt_consent {
+            params.insert("prompt", "consent");
+        }
+        if let Some(login_hint) = &opts.login_hint {
+            params.insert("login_hint", login_hint);
+        }
 
-        let mut scope = String::from("profile");
-        if readonly {
-            scope.push_str(" https://www.googleapis.com/auth/gmail.readonly");
-        } else {
-            scope.push_str(" https://www.googleapis.com/auth/gmail.modify");
+        /*
+         * If PKCE is enabled, generate a new code verifier for this request.
+         */
+        let pkce = self.inner.lock().unwrap().pkce;
+        let verifier = pkce.map(|_| random_string(64));
+        let challenge = pkce
+            .zip(verifier.as_deref())
+            .map(|(method, v)| (method, method.challenge(v)));
+        if let Some((method, challenge)) = &challenge {
+            params.insert("code_challenge", challenge);
+            params.insert("code_challenge_method", method.name());
         }
+
+        let scope = opts.scope_string();
         params.insert("scope", &scope);
 
         /*
@@ -149,113 +935,1602 @@ impl GAuth {
             .client
             .get(self.auth_uri.as_ref())
             .query(&params)
-            .build()?;
-        Ok(req.url().to_string())
+            .build()
+            .map_err(|e| {
+                AuthError::Other(format!("authorisation URL: {}", e))
+            })?;
+        Ok((req.url().to_string(), verifier))
     }
 
     /**
-     * Exchange an authentication code from the user's browser to get a
-     * permanent refresh token we can store.
+     * Begin an installed application authorisation that uses a redirect to a
+     * listener on an ephemeral port on the loopback interface, rather than the
+     * deprecated OOB redirect URI.  The URL from Loopback::url() should be
+     * given to the user to open in their browser, and then Loopback::finish()
+     * will wait for the browser to return with the authentication code and
+     * exchange it.
      */
-    pub async fn exchange(&self, code: &str) -> Result<()> {
-        let mut params: HashMap<&str, &str> = HashMap::new();
-        params.insert("code", code);
-        params.insert("client_id", &self.client_id);
-        params.insert("client_secret", &self.client_secret);
-        params.insert("redirect_uri", &self.redirect_uri);
-        params.insert("grant_type", "authorization_code");
-
-        let res = self
-            .client
-            .post(self.token_uri.as_ref())
-            .form(&params)
-            .send()
-            .await?;
-
-        if res.status() != StatusCode::OK {
-            bail!("oddball response: {:#?}", res);
-        }
-        debug!(self.log, "exchange response: {:#?}", &res);
+    pub async fn loopback(
+        &self,
+        readonly: bool,
+    ) -> Result<Loopback, AuthError> {
+        self.loopback_with(&AuthOptions::basic(readonly)).await
+    }
 
-        let oj: serde_json::Value = res.json().await?;
-        debug!(self.log, "exchange body: {:#?}", &oj);
+    /**
+     * Begin a loopback redirect authorisation as per loopback(), but with
+     * control over the scopes and other options in the request.
+     */
+    pub async fn loopback_with(
+        &self,
+        opts: &AuthOptions,
+    ) -> Result<Loopback, AuthError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .map_err(|e| AuthError::Listener(e.to_string()))?;
+        let port = listener
+            .local_addr()
+            .map_err(|e| AuthError::Listener(e.to_string()))?
+            .port();
+        let redirect_uri = format!("http://127.0.0.1:{}", port);
+        let state = random_string(32);
+        let (url, verifier) =
+            self.auth_url(opts, &redirect_uri, Some(&state))?;
 
-        let o: RExchange = serde_json::from_value(oj)?;
+        debug!(self.log, "loopback listener for {}", redirect_uri);
 
-        let et = SystemTime::now()
-            .checked_add(Duration::from_secs(o.expires_in * 2 / 3))
-            .ok_or_else(|| anyhow!("invalid expiry time"))?;
+        Ok(Loopback {
+            auth: self.clone(),
+            listener,
+            redirect_uri,
+            state,
+            verifier,
+            scopes: opts.scopes.clone(),
+            url,
+        })
+    }
+
+    /**
+     * Exchange an authentication code from the user's browser to get a
+     * permanent refresh token we can store.
+     */
+    pub async fn exchange(&self, code: &str) -> Result<(), AuthError> {
+        let (verifier, scopes) = {
+            let i = self.inner.lock().unwrap();
+            if i.pkce.is_some() && i.pkce_verifier.is_none() {
+                return Err(AuthError::Other(
+                    "PKCE enabled, but no authorisation URL was built".into(),
+                ));
+            }
+            (i.pkce_verifier.clone(), i.requested_scopes.clone())
+        };
+
+        self.exchange_common(
+            code,
+            &self.redirect_uri,
+            verifier.as_deref(),
+            &scopes,
+        )
+        .await?;
 
         let mut i = self.inner.lock().unwrap();
+        if i.pkce_verifier == verifier {
+            i.pkce_verifier = None;
+        }
+        Ok(())
+    }
+
+    async fn exchange_common(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        verifier: Option<&str>,
+        scopes: &BTreeSet<Scope>,
+    ) -> Result<(), AuthError> {
+        if self.service_account.is_some() {
+            return Err(AuthError::Other(
+                "service accounts do not use interactive authorisation".into(),
+            ));
+        }
+
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("code", code);
+        params.insert("client_id", &self.client_id);
+        if !self.client_secret.is_empty() {
+            params.insert("client_secret", &self.client_secret);
+        }
+        params.insert("redirect_uri", redirect_uri);
+        params.insert("grant_type", "authorization_code");
+        if let Some(verifier) = verifier {
+            params.insert("code_verifier", verifier);
+        }
 
-        i.refresh_token = o.refresh_token;
-        i.access_token = o.access_token;
-        i.expiry = Some(et);
+        let o: RExchange = self.token_request(&params).await?;
+
+        self.exchanged(o, scopes).await
+    }
+
+    /**
+     * Keep the tokens from a successful exchange.  If the response does not
+     * list the granted scopes, they are the ones we requested.
+     */
+    async fn exchanged(
+        &self,
+        o: RExchange,
+        requested: &BTreeSet<Scope>,
+    ) -> Result<(), AuthError> {
+        let et = expiry_time(o.expires_in)?;
+
+        let generation = {
+            let mut i = self.inner.lock().unwrap();
+
+            i.refresh_token = o.refresh_token;
+            i.access_token = o.access_token;
+            i.expiry = Some(et);
+            i.scopes = if o.scope.is_empty() {
+                requested.clone()
+            } else {
+                Scope::parse_list(&o.scope)
+            };
+            i.generation
+        };
+
+        /*
+         * The refresh token is precious, so if we cannot store it we should
+         * report failure.
+         */
+        self.persist(generation).await?;
 
         Ok(())
     }
 
-    pub async fn refresh(&self) -> Result<()> {
-        let refresh_token = self.inner.lock().unwrap().refresh_token.clone();
+    /**
+     * Begin a device authorisation (RFC 8628), for use where the user cannot
+     * open a browser on the machine we are running on.  The user code and
+     * verification URL from the result should be shown to the user, and then
+     * device_poll() will wait for them to complete the authorisation.
+     */
+    pub async fn device_start(
+        &self,
+        opts: &AuthOptions,
+    ) -> Result<DeviceCode, AuthError> {
+        if self.service_account.is_some() {
+            return Err(AuthError::Other(
+                "service accounts do not use interactive authorisation".into(),
+            ));
+        }
+        if opts.scopes.is_empty() {
+            return Err(AuthError::Other(
+                "at least one scope is required".into(),
+            ));
+        }
 
+        let scope = opts.scope_string();
         let mut params: HashMap<&str, &str> = HashMap::new();
         params.insert("client_id", &self.client_id);
-        params.insert("client_secret", &self.client_secret);
-        params.insert("refresh_token", &refresh_token);
-        params.insert("grant_type", "refresh_token");
+        params.insert("scope", &scope);
 
-        let res = self
-            .client
-            .post(self.token_uri.as_ref())
-            .form(&params)
-            .send()
-            .await?;
+        let o: RDeviceCode =
+            self.post_form(&self.device_auth_uri, &params).await?;
 
-        if res.status() != reqwest::StatusCode::OK {
-            bail!("oddball response: {:#?}", res);
+        Ok(DeviceCode {
+            device_code: o.device_code,
+            user_code: o.user_code,
+            verification_url: o.verification_url,
+            expires_at: SystemTime::now()
+                .checked_add(Duration::from_secs(o.expires_in))
+                .ok_or_else(|| {
+                    AuthError::Other("invalid expiry time".into())
+                })?,
+            interval: Duration::from_secs(o.interval.max(1)),
+            slow_down: DEVICE_SLOW_DOWN,
+            scopes: opts.scopes.clone(),
+        })
+    }
+
+    /**
+     * Poll the token endpoint until the user has completed (or denied) the
+     * device authorisation, or until the device code expires.  On success, we
+     * have a refresh token just as if exchange() had been used.
+     */
+    pub async fn device_poll(&self, dc: &DeviceCode) -> Result<(), AuthError> {
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("client_id", &self.client_id);
+        if !self.client_secret.is_empty() {
+            params.insert("client_secret", &self.client_secret);
         }
+        params.insert("device_code", &dc.device_code);
+        params.insert("grant_type", DEVICE_CODE);
 
-        let o: RRefresh = res.json().await?;
+        let mut interval = dc.interval;
 
-        let et = SystemTime::now()
-            .checked_add(Duration::from_secs(o.expires_in * 2 / 3))
-            .ok_or_else(|| anyhow!("invalid expiry time"))?;
+        loop {
+            tokio::time::sleep(interval).await;
 
-        let mut i = self.inner.lock().unwrap();
+            if SystemTime::now() > dc.expires_at {
+                return Err(AuthError::OAuth {
+                    status: 400,
+                    error: "expired_token".into(),
+                    description: "device code expired".into(),
+                });
+            }
+
+            match self.token_request::<RExchange>(&params).await {
+                Ok(o) => {
+                    info!(self.log, "device authorisation complete");
+                    return self.exchanged(o, &dc.scopes).await;
+                }
+                Err(AuthError::OAuth { error, .. })
+                    if error == "authorization_pending" =>
+                {
+                    debug!(self.log, "device authorisation pending");
+                }
+                Err(AuthError::OAuth { error, .. }) if error == "slow_down" => {
+                    interval += dc.slow_down;
+                    debug!(self.log, "slowing down to {:?}", interval);
+                }
+                Err(AuthError::RateLimited { retry_after }) => {
+                    interval += dc.slow_down;
+                    if let Some(ra) = retry_after {
+                        interval = interval.max(ra);
+                    }
+                    debug!(
+                        self.log,
+                        "rate limited; polling every {:?}", interval
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn refresh(&self) -> Result<(), AuthError> {
+        let (refresh_token, generation) = {
+            let i = self.inner.lock().unwrap();
+            (i.refresh_token.clone(), i.generation)
+        };
+        let assertion;
+
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        if let Some(sa) = &self.service_account {
+            /*
+             * Service accounts have no refresh token.  Instead, we sign a new
+             * assertion each time we need a new access token.
+             */
+            assertion = sa.assertion(self.token_uri.as_str())?;
+            params.insert("grant_type", JWT_BEARER);
+            params.insert("assertion", &assertion);
+        } else {
+            if refresh_token.is_empty() {
+                return Err(AuthError::NoRefreshToken);
+            }
+
+            params.insert("client_id", &self.client_id);
+            if !self.client_secret.is_empty() {
+                params.insert("client_secret", &self.client_secret);
+            }
+            params.insert("refresh_token", &refresh_token);
+            params.insert("grant_type", "refresh_token");
+        }
 
-        i.access_token = o.access_token;
-        i.expiry = Some(et);
+        let o: RRefresh = self.token_request(&params).await?;
+
+        let et = expiry_time(o.expires_in)?;
+
+        let rotated = {
+            let mut i = self.inner.lock().unwrap();
+
+            if i.generation != generation {
+                /*
+                 * Our tokens were revoked while this refresh was in flight.
+                 */
+                debug!(self.log, "discarding refresh after revocation");
+                return Err(AuthError::NoRefreshToken);
+            }
+
+            i.access_token = o.access_token;
+            i.expiry = Some(et);
+            if !o.scope.is_empty() {
+                i.scopes = Scope::parse_list(&o.scope);
+            } else if let Some(sa) = &self.service_account {
+                i.scopes = Scope::parse_list(&sa.scope);
+            }
+
+            match o.refresh_token {
+                Some(rt) if rt != i.refresh_token => {
+                    i.refresh_token = rt;
+                    true
+                }
+                _ => false,
+            }
+        };
+
+        if rotated {
+            /*
+             * The old refresh token may no longer work, so we must not lose
+             * the new one.
+             */
+            info!(self.log, "refresh token was rotated by the server");
+            self.persist(generation).await?;
+        } else if let Err(e) = self.persist(generation).await {
+            /*
+             * We have a usable access token, even if we are unable to store
+             * it, so just complain and carry on.
+             */
+            warn!(self.log, "could not store refreshed token: {:?}", e);
+        }
 
         Ok(())
     }
 
-    pub async fn check_refresh(&self) -> Result<()> {
-        let et = self.inner.lock().unwrap().expiry;
+    /**
+     * Revoke our authorisation at the authorisation server, and forget all of
+     * our tokens, including any in the token store.  Revoking a refresh token
+     * also revokes any access tokens issued with it, so we only revoke the
+     * access token if we have no refresh token.  A refresh that is in flight
+     * will fail with AuthError::NoRefreshToken, rather than keep the tokens
+     * it obtains.
+     */
+    pub async fn revoke(&self) -> Result<(), AuthError> {
+        let token = {
+            let i = self.inner.lock().unwrap();
+            if !i.refresh_token.is_empty() {
+                i.refresh_token.clone()
+            } else {
+                i.access_token.clone()
+            }
+        };
+
+        if !token.is_empty() {
+            let res = self
+                .client
+                .post(self.revoke_uri.as_ref())
+                .form(&[("token", token.as_str())])
+                .send()
+                .await
+                .map_err(AuthError::request)?;
+
+            let status = res.status();
+            debug!(self.log, "revoke response status: {}", status);
 
-        let refresh = if self.inner.lock().unwrap().access_token == "" {
-            debug!(self.log, "no auth token yet, refreshing");
-            true
-        } else if let Some(et) = et {
-            if SystemTime::now() > et {
-                debug!(self.log, "auth token expiry pending, refreshing");
+            if status != StatusCode::OK {
+                let body = res.bytes().await.map_err(AuthError::request)?;
+
+                match AuthError::from_response(status, None, &body) {
+                    AuthError::OAuth { error, .. }
+                        if error == "invalid_token" =>
+                    {
+                        /*
+                         * The token has already expired or been revoked, so
+                         * there is nothing more for the server to do.
+                         */
+                        debug!(self.log, "token was already invalid");
+                    }
+                    e => return Err(e),
+                }
+            }
+        }
+
+        {
+            let mut i = self.inner.lock().unwrap();
+            i.refresh_token.clear();
+            i.access_token.clear();
+            i.expiry = None;
+            i.pkce_verifier = None;
+            i.scopes.clear();
+            i.generation += 1;
+        }
+
+        self.with_store(None, |s| s.clear()).await?;
+
+        info!(self.log, "authorisation revoked");
+        Ok(())
+    }
+
+    /**
+     * Make a request to the token endpoint, and interpret the response.
+     */
+    async fn token_request<T: DeserializeOwned>(
+        &self,
+        params: &HashMap<&str, &str>,
+    ) -> Result<T, AuthError> {
+        self.post_form(&self.token_uri, params).await
+    }
+
+    async fn post_form<T: DeserializeOwned>(
+        &self,
+        url: &reqwest::Url,
+        params: &HashMap<&str, &str>,
+    ) -> Result<T, AuthError> {
+        let res = self
+            .client
+            .post(url.as_ref())
+            .form(params)
+            .send()
+            .await
+            .map_err(AuthError::request)?;
+
+        let status = res.status();
+        debug!(self.log, "response status from {}: {}", url, status);
+
+        let retry_after = retry_after(res.headers());
+
+        let body = res.bytes().await.map_err(AuthError::request)?;
+
+        if status != StatusCode::OK {
+            return Err(AuthError::from_response(status, retry_after, &body));
+        }
+
+        serde_json::from_slice(&body).map_err(|e| {
+            AuthError::Other(format!("parsing response from {}: {}", url, e))
+        })
+    }
+
+    /**
+     * Refresh the access token if we do not have one, or if it is due to
+     * expire.  If a refresh is already in progress, we wait for it to finish
+     * rather than start another one, and share in its result; a failure is
+     * reported to every waiter, not retried by each of them.
+     */
+    pub async fn check_refresh(&self) -> Result<(), AuthError> {
+        let fut = {
+            let mut i = self.inner.lock().unwrap();
+
+            let refresh = if i.access_token.is_empty() {
+                debug!(self.log, "no auth token yet, refreshing");
                 true
+            } else if let Some(et) = i.expiry {
+                if SystemTime::now() > et {
+                    debug!(self.log, "auth token expiry pending, refreshing");
+                    true
+                } else {
+                    false
+                }
             } else {
+                debug!(self.log, "check_refresh: no expiry time?");
                 false
+            };
+
+            if !refresh {
+                return Ok(());
+            }
+
+            if let Some(fut) =
+                i.refreshing.as_ref().and_then(WeakShared::upgrade)
+            {
+                debug!(self.log, "waiting for in-flight refresh");
+                fut
+            } else {
+                let gauth = self.clone();
+                let fut = async move {
+                    let res = gauth.refresh().await;
+                    gauth.inner.lock().unwrap().refreshing = None;
+                    res
+                }
+                .boxed()
+                .shared();
+
+                i.refreshing = fut.downgrade();
+                fut
             }
-        } else {
-            debug!(self.log, "check_refresh: no expiry time?");
-            false
         };
 
-        if refresh {
-            self.refresh().await?;
+        fut.await
+    }
+}
+
+/**
+ * How long to wait for a request on a connection to the loopback listener.
+ */
+const LOOPBACK_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * An in-progress loopback redirect authorisation, as started by
+ * GAuth::loopback().
+ */
+pub struct Loopback {
+    auth: GAuth,
+    listener: TcpListener,
+    redirect_uri: String,
+    state: String,
+    /*
+     * The PKCE code verifier for our URL, if PKCE is enabled.  We keep it
+     * here, rather than in the GAuth, so that other authorisation requests
+     * made in the meantime do not replace it.
+     */
+    verifier: Option<String>,
+    scopes: BTreeSet<Scope>,
+    url: String,
+}
+
+impl Loopback {
+    /**
+     * The URL the user should open in their browser.
+     */
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn redirect_uri(&self) -> &str {
+        &self.redirect_uri
+    }
+
+    /**
+     * Wait for the browser to be redirected back to our listener, check that
+     * the state parameter matches the one we sent, and exchange the
+     * authentication code for tokens.  Requests that do not carry our state
+     * are rejected without ending the wait.
+     */
+    pub async fn finish(self) -> Result<(), AuthError> {
+        let log = &self.auth.log;
+
+        /*
+         * A browser may open connections speculatively and never send a
+         * request on them, and anything else on this machine can connect to
+         * the listener too.  Read from each connection separately, with a
+         * time limit, so that an idle connection cannot hold up the real
+         * redirect.
+         */
+        let mut reading = FuturesUnordered::new();
+
+        loop {
+            let (mut sock, addr, res) = tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (mut sock, addr) = accepted
+                        .map_err(|e| AuthError::Listener(e.to_string()))?;
+                    debug!(log, "loopback connection from {}", addr);
+
+                    reading.push(async move {
+                        let res = tokio::time::timeout(
+                            LOOPBACK_READ_TIMEOUT,
+                            read_request_query(&mut sock),
+                        )
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(AuthError::Listener(
+                                "timed out reading request".into(),
+                            ))
+                        });
+                        (sock, addr, res)
+                    });
+                    continue;
+                }
+                Some(r) = reading.next(), if !reading.is_empty() => r,
+            };
+
+            let query = match res {
+                Ok(Some(query)) => query,
+                Ok(None) => {
+                    /*
+                     * Browsers will often make other requests (e.g., for
+                     * "/favicon.ico") that we should just ignore.
+                     */
+                    respond(&mut sock, "404 Not Found", "Not found.").await;
+                    continue;
+                }
+                Err(e) => {
+                    debug!(log, "loopback request error: {:?}", e);
+                    respond(&mut sock, "400 Bad Request", "Bad request.").await;
+                    continue;
+                }
+            };
+
+            if query.get("state").map(String::as_str) != Some(&self.state) {
+                /*
+                 * Anything on this machine can connect to the listener, so a
+                 * request without our state is not the browser returning from
+                 * the authorisation server.  Turn it away, but keep waiting
+                 * for the real redirect.
+                 */
+                warn!(log, "loopback request from {} with wrong state", addr);
+                respond(&mut sock, "400 Bad Request", "State mismatch.").await;
+                continue;
+            }
+
+            if let Some(e) = query.get("error") {
+                respond(&mut sock, "403 Forbidden", "Authorisation denied.")
+                    .await;
+                return Err(AuthError::Redirect {
+                    error: e.to_string(),
+                    description: query
+                        .get("error_description")
+                        .cloned()
+                        .unwrap_or_default(),
+                });
+            }
+
+            let code = if let Some(code) = query.get("code") {
+                code
+            } else {
+                respond(&mut sock, "400 Bad Request", "Code missing.").await;
+                return Err(AuthError::Other(
+                    "loopback redirect did not include a code".into(),
+                ));
+            };
+
+            match self
+                .auth
+                .exchange_common(
+                    code,
+                    &self.redirect_uri,
+                    self.verifier.as_deref(),
+                    &self.scopes,
+                )
+                .await
+            {
+                Ok(()) => {
+                    info!(log, "loopback authorisation complete");
+                    respond(
+                        &mut sock,
+                        "200 OK",
+                        "Authorisation complete; you may close this window.",
+                    )
+                    .await;
+                    return Ok(());
+                }
+                Err(e) => {
+                    respond(
+                        &mut sock,
+                        "500 Internal Server Error",
+                        "Authorisation failed.",
+                    )
+                    .await;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Read a HTTP request from the browser and return the query parameters, if
+ * there were any.
+ */
+async fn read_request_query(
+    sock: &mut TcpStream,
+) -> Result<Option<HashMap<String, String>>, AuthError> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    let path = loop {
+        if buf.len() > 16 * 1024 {
+            return Err(AuthError::Other("request too long".into()));
         }
 
-        Ok(())
+        let mut chunk = [0u8; 1024];
+        let sz = sock
+            .read(&mut chunk)
+            .await
+            .map_err(|e| AuthError::Listener(e.to_string()))?;
+        if sz == 0 {
+            return Err(AuthError::Other("unexpected end of request".into()));
+        }
+        buf.extend_from_slice(&chunk[..sz]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        if req
+            .parse(&buf)
+            .map_err(|e| AuthError::Other(e.to_string()))?
+            .is_complete()
+        {
+            if req.method != Some("GET") {
+                return Err(AuthError::Other(format!(
+                    "unexpected method {:?}",
+                    req.method
+                )));
+            }
+            break req.path.unwrap_or("/").to_string();
+        }
+    };
+
+    let url = reqwest::Url::parse("http://127.0.0.1/")
+        .and_then(|u| u.join(&path))
+        .map_err(|e| AuthError::Other(format!("request path: {}", e)))?;
+    if url.query().is_none() {
+        return Ok(None);
     }
+
+    Ok(Some(url.query_pairs().into_owned().collect()))
+}
+
+async fn respond(sock: &mut TcpStream, status: &str, msg: &str) {
+    let res = format!(
+        "HTTP/1.1 {}\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}",
+        status,
+        msg.len(),
+        msg,
+    );
+
+    /*
+     * If the browser has gone away, there is nobody to tell.
+     */
+    sock.write_all(res.as_bytes()).await.ok();
+    sock.shutdown().await.ok();
 }
 
-fn reqvalurl<S: AsRef<str>>(val: S, n: &str) -> Result<reqwest::Url> {
-    reqwest::Url::parse(val.as_ref())
-        .map_err(|e| anyhow!("client_id.json URL \"{}\" invalid: {}", n, e))
+/**
+ * Determine when we should next refresh an access token that expires in the
+ * specified number of seconds.  We leave ourselves a healthy margin, so that
+ * the token does not expire while a request is in flight.
+ */
+fn expiry_time(expires_in: u64) -> Result<SystemTime, AuthError> {
+    SystemTime::now()
+        .checked_add(Duration::from_secs(expires_in * 2 / 3))
+        .ok_or_else(|| AuthError::Other("invalid expiry time".into()))
+}
+
+fn build_client() -> Client {
+    ClientBuilder::new()
+        .tcp_keepalive(Duration::from_secs(30))
+        .connect_timeout(Duration::from_secs(30))
+        .redirect(redirect::Policy::none())
+        .build()
+        .expect("build client")
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn reqvalurl<S: AsRef<str>>(
+    val: S,
+    n: &str,
+) -> Result<reqwest::Url, AuthError> {
+    reqwest::Url::parse(val.as_ref()).map_err(|e| {
+        AuthError::Config(format!(
+            "client_id.json URL \"{}\" invalid: {}",
+            n, e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{logger, Response, Server};
+    use crate::token::FileTokenStore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /**
+     * A client configuration with the token, revocation, and device
+     * authorisation endpoints on the specified server.
+     */
+    fn config(url: &str) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "installed": {
+                "client_id": "client",
+                "client_secret": "secret",
+                "auth_uri": "https://accounts.example.com/auth",
+                "token_uri": format!("{}/token", url),
+                "revoke_uri": format!("{}/revoke", url),
+                "device_auth_uri": format!("{}/device", url),
+            }
+        }))
+        .unwrap()
+    }
+
+    async fn token_server() -> Server {
+        Server::start(|_| {
+            Response::json(
+                200,
+                &serde_json::json!({
+                    "access_token": "access",
+                    "expires_in": 3600,
+                    "refresh_token": "refresh",
+                    "scope": "https://mail.google.com/",
+                    "token_type": "Bearer",
+                }),
+            )
+        })
+        .await
+    }
+
+    fn query(url: &str) -> HashMap<String, String> {
+        reqwest::Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn loopback_exchange() {
+        let ts = token_server().await;
+        let path = std::env::temp_dir()
+            .join(format!("rgmail-loopback-{}.json", std::process::id()));
+        let auth = GAuth::new(logger(), config(ts.url()))
+            .unwrap()
+            .with_token_store(FileTokenStore::new(&path))
+            .unwrap();
+        auth.set_pkce(Some(PkceMethod::S256));
+
+        let lb = auth.loopback(false).await.unwrap();
+        let q = query(lb.url());
+        let redirect_uri = lb.redirect_uri().to_string();
+        assert_eq!(q["redirect_uri"], redirect_uri);
+        let finish = tokio::spawn(lb.finish());
+
+        /*
+         * Building another authorisation URL must not disturb the verifier
+         * for the loopback request.
+         */
+        auth.auth_token(false).unwrap();
+
+        /*
+         * Requests with the wrong state, or none at all, must be turned away
+         * without ending the wait for the real redirect.
+         */
+        let client = reqwest::Client::new();
+        for path in ["/?state=wrong&code=bogus", "/?code=bogus"] {
+            let res = client
+                .get(format!("{}{}", redirect_uri, path))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(ts.requests().is_empty());
+        assert!(!finish.is_finished());
+
+        let res = client
+            .get(format!("{}/?state={}&code=good", redirect_uri, q["state"]))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        finish.await.unwrap().unwrap();
+
+        assert_eq!(auth.access_token(), "access");
+        assert_eq!(auth.refresh_token(), "refresh");
+
+        let st = FileTokenStore::new(&path).load().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(st.access_token, "access");
+        assert_eq!(st.refresh_token, "refresh");
+
+        let reqs = ts.requests();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "POST");
+        assert_eq!(reqs[0].path, "/token");
+        assert_eq!(
+            reqs[0].headers["content-type"],
+            "application/x-www-form-urlencoded"
+        );
+        let form = reqs[0].form();
+        assert_eq!(form["grant_type"], "authorization_code");
+        assert_eq!(form["code"], "good");
+        assert_eq!(form["client_id"], "client");
+        assert_eq!(form["redirect_uri"], redirect_uri);
+        assert_eq!(
+            PkceMethod::S256.challenge(&form["code_verifier"]),
+            q["code_challenge"]
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_url_options() {
+        /*
+         * The token server may leave out the scope, in which case we have
+         * been granted the scopes we asked for.
+         */
+        let ts = Server::start(|_| {
+            Response::json(
+                200,
+                &serde_json::json!({
+                    "access_token": "access",
+                    "expires_in": 3600,
+                    "refresh_token": "refresh",
+                    "token_type": "Bearer",
+                }),
+            )
+        })
+        .await;
+        let auth = GAuth::new(logger(), config(ts.url())).unwrap();
+
+        let url = auth
+            .auth_token_with(
+                &AuthOptions::new()
+                    .scope(Scope::GmailReadonly)
+                    .scope(Scope::Email)
+                    .include_granted_scopes(true)
+                    .offline(true)
+                    .prompt_consent(true)
+                    .login_hint("user@example.com"),
+            )
+            .unwrap();
+        assert!(url.starts_with("https://accounts.example.com/auth?"));
+
+        let q = query(&url);
+        assert_eq!(q["client_id"], "client");
+        assert_eq!(q["redirect_uri"], "urn:ietf:wg:oauth:2.0:oob");
+        assert_eq!(q["response_type"], "code");
+        assert_eq!(
+            q["scope"],
+            "email https://www.googleapis.com/auth/gmail.readonly"
+        );
+        assert_eq!(q["include_granted_scopes"], "true");
+        assert_eq!(q["access_type"], "offline");
+        assert_eq!(q["prompt"], "consent");
+        assert_eq!(q["login_hint"], "user@example.com");
+        assert!(!q.contains_key("state"));
+        assert!(!q.contains_key("code_challenge"));
+
+        auth.exchange("code").await.unwrap();
+        assert_eq!(
+            auth.granted_scopes(),
+            [Scope::Email, Scope::GmailReadonly].into_iter().collect()
+        );
+        assert!(auth.has_scope(&Scope::GmailMetadata));
+        assert!(!auth.has_scope(&Scope::GmailModify));
+
+        /*
+         * Without any options, only the required parameters are included.
+         */
+        let q = query(&auth.auth_token(true).unwrap());
+        assert_eq!(
+            q.keys().map(String::as_str).collect::<BTreeSet<_>>(),
+            ["client_id", "redirect_uri", "response_type", "scope"]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    /**
+     * A stub for the device authorisation and token endpoints.  Token
+     * requests are answered in turn with each of the specified responses, and
+     * then with tokens.  A 429 response asks us to retry straight away.
+     */
+    async fn device_server(pending: Vec<(u16, &'static str)>) -> Server {
+        let n = AtomicUsize::new(0);
+        Server::start(move |req| {
+            if req.path == "/device" {
+                return Response::json(
+                    200,
+                    &serde_json::json!({
+                        "device_code": "device",
+                        "user_code": "ABCD-EFGH",
+                        "verification_url": "https://example.com/device",
+                        "expires_in": 1800,
+                        "interval": 5,
+                    }),
+                );
+            }
+
+            match pending.get(n.fetch_add(1, Ordering::SeqCst)) {
+                Some(&(429, _)) => {
+                    Response::new(429).header("Retry-After", "0")
+                }
+                Some(&(status, error)) => {
+                    let mut res = Response::new(status);
+                    if !error.is_empty() {
+                        res = res
+                            .header("Content-Type", "application/json")
+                            .body(
+                                serde_json::json!({ "error": error })
+                                    .to_string(),
+                            );
+                    }
+                    res
+                }
+                None => Response::json(
+                    200,
+                    &serde_json::json!({
+                        "access_token": "access",
+                        "expires_in": 3600,
+                        "refresh_token": "refresh",
+                        "scope": "https://mail.google.com/",
+                        "token_type": "Bearer",
+                    }),
+                ),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn device_poll() {
+        let ts = device_server(vec![
+            (428, "authorization_pending"),
+            (403, "slow_down"),
+            (429, ""),
+            (428, "authorization_pending"),
+        ])
+        .await;
+        let path = store_path("device");
+        let auth = GAuth::new(logger(), config(ts.url()))
+            .unwrap()
+            .with_token_store(FileTokenStore::new(&path))
+            .unwrap();
+
+        let mut dc = auth
+            .device_start(&AuthOptions::new().scope(Scope::Mail))
+            .await
+            .unwrap();
+        assert_eq!(dc.user_code(), "ABCD-EFGH");
+        assert_eq!(dc.verification_url(), "https://example.com/device");
+        assert_eq!(dc.interval, Duration::from_secs(5));
+        assert_eq!(dc.slow_down, DEVICE_SLOW_DOWN);
+
+        /*
+         * Poll quickly, so that the test need not wait for seconds.
+         */
+        dc.interval = Duration::from_millis(10);
+        dc.slow_down = Duration::from_millis(10);
+        auth.device_poll(&dc).await.unwrap();
+
+        assert_eq!(auth.refresh_token(), "refresh");
+        assert!(auth.has_scope(&Scope::GmailModify));
+        let st = FileTokenStore::new(&path).load().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(st.refresh_token, "refresh");
+
+        let reqs = ts.requests();
+        assert_eq!(reqs.len(), 6);
+        assert_eq!(reqs[0].path, "/device");
+        assert_eq!(reqs[0].form()["scope"], "https://mail.google.com/");
+        for req in &reqs[1..] {
+            assert_eq!(req.path, "/token");
+            let form = req.form();
+            assert_eq!(form["grant_type"], DEVICE_CODE);
+            assert_eq!(form["device_code"], "device");
+            assert_eq!(form["client_id"], "client");
+        }
+    }
+
+    #[tokio::test]
+    async fn device_poll_errors() {
+        let ts = device_server(vec![
+            (428, "authorization_pending"),
+            (400, "access_denied"),
+        ])
+        .await;
+        let auth = GAuth::new(logger(), config(ts.url())).unwrap();
+        let mut dc = auth
+            .device_start(&AuthOptions::new().scope(Scope::Mail))
+            .await
+            .unwrap();
+        dc.interval = Duration::from_millis(10);
+
+        /*
+         * The user declining is reported as soon as we hear of it.
+         */
+        assert!(matches!(
+            auth.device_poll(&dc).await,
+            Err(AuthError::OAuth { status: 400, error, .. })
+                if error == "access_denied"
+        ));
+        assert_eq!(ts.requests().len(), 3);
+
+        /*
+         * Once the device code expires, there is no point in polling.
+         */
+        dc.expires_at = SystemTime::now();
+        assert!(matches!(
+            auth.device_poll(&dc).await,
+            Err(AuthError::OAuth { error, .. }) if error == "expired_token"
+        ));
+        assert_eq!(ts.requests().len(), 3);
+        assert!(auth.refresh_token().is_empty());
+    }
+
+    #[test]
+    fn auth_error_classification() {
+        fn oauth(error: &str) -> Vec<u8> {
+            serde_json::json!({
+                "error": error,
+                "error_description": "denied",
+            })
+            .to_string()
+            .into_bytes()
+        }
+
+        const RA: Option<Duration> = Some(Duration::from_secs(30));
+
+        /*
+         * Each case is the status and body of a response, the kind of error
+         * we expect, and whether that error needs the user to authorise us
+         * again, or is transient.
+         */
+        type Check = fn(&AuthError) -> bool;
+        let cases: Vec<(u16, Vec<u8>, Check, bool, bool)> = vec![
+            (
+                429,
+                b"slow down".to_vec(),
+                |e| matches!(e, AuthError::RateLimited { retry_after: RA }),
+                false,
+                true,
+            ),
+            (
+                400,
+                oauth("rate_limit_exceeded"),
+                |e| matches!(e, AuthError::RateLimited { retry_after: RA }),
+                false,
+                true,
+            ),
+            (
+                503,
+                b"unavailable".to_vec(),
+                |e| {
+                    matches!(
+                        e,
+                        AuthError::Transient { status: 503, message }
+                            if message == "unavailable"
+                    )
+                },
+                false,
+                true,
+            ),
+            (
+                500,
+                oauth("invalid_grant"),
+                |e| matches!(e, AuthError::Transient { status: 500, .. }),
+                false,
+                true,
+            ),
+            (
+                400,
+                oauth("invalid_grant"),
+                |e| matches!(e, AuthError::InvalidGrant(d) if d == "denied"),
+                true,
+                false,
+            ),
+            (
+                401,
+                oauth("invalid_client"),
+                |e| matches!(e, AuthError::InvalidClient(d) if d == "denied"),
+                false,
+                false,
+            ),
+            (
+                400,
+                oauth("unauthorized_client"),
+                |e| matches!(e, AuthError::InvalidClient(_)),
+                false,
+                false,
+            ),
+            (
+                400,
+                oauth("invalid_scope"),
+                |e| {
+                    matches!(
+                        e,
+                        AuthError::OAuth { status: 400, error, description }
+                            if error == "invalid_scope"
+                                && description == "denied"
+                    )
+                },
+                false,
+                false,
+            ),
+            (
+                400,
+                b"<html>Bad Request</html>".to_vec(),
+                |e| matches!(e, AuthError::Other(_)),
+                false,
+                false,
+            ),
+        ];
+
+        for (status, body, check, reconsent, transient) in cases {
+            let e = AuthError::from_response(
+                StatusCode::from_u16(status).unwrap(),
+                RA,
+                &body,
+            );
+
+            assert!(check(&e), "{}: {:?}", status, e);
+            assert_eq!(e.needs_reconsent(), reconsent, "{:?}", e);
+            assert_eq!(e.is_transient(), transient, "{:?}", e);
+        }
+
+        assert!(AuthError::NoRefreshToken.needs_reconsent());
+    }
+
+    #[tokio::test]
+    async fn auth_error_request() {
+        /*
+         * Nothing is listening on the port of a listener we have closed, so
+         * we cannot connect; that may be different next time.
+         */
+        let port = {
+            let l = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let auth =
+            GAuth::new(logger(), config(&format!("http://127.0.0.1:{}", port)))
+                .unwrap();
+        auth.set_refresh_token("refresh");
+        let e = auth.refresh().await.unwrap_err();
+        assert!(matches!(&e, AuthError::Request(re) if re.is_connect()));
+        assert!(e.is_transient());
+
+        /*
+         * A response that is not what we expect will not improve with time.
+         */
+        let ts = Server::start(|_| Response::new(200).body("[]")).await;
+        let res = reqwest::get(ts.url()).await.unwrap();
+        let e = AuthError::request(res.json::<RRefresh>().await.err().unwrap());
+        assert!(!e.is_transient());
+    }
+
+    #[tokio::test]
+    async fn refresh_abandoned() {
+        let ts = token_server().await;
+        let auth = GAuth::new(logger(), config(ts.url())).unwrap();
+        auth.set_refresh_token("refresh");
+
+        /*
+         * Start a refresh and then give up on it before it completes.  The
+         * abandoned refresh must not keep the GAuth state alive, and must
+         * not prevent a later refresh.
+         */
+        assert!(auth.check_refresh().now_or_never().is_none());
+        assert_eq!(Arc::strong_count(&auth.inner), 1);
+
+        auth.check_refresh().await.unwrap();
+        assert_eq!(auth.access_token(), "access");
+        assert!(auth.inner.lock().unwrap().refreshing.is_none());
+    }
+
+    #[tokio::test]
+    async fn loopback_idle() {
+        let ts = token_server().await;
+        let auth = GAuth::new(logger(), config(ts.url())).unwrap();
+
+        let lb = auth.loopback(false).await.unwrap();
+        let q = query(lb.url());
+        let redirect_uri = lb.redirect_uri().to_string();
+        let finish = tokio::spawn(lb.finish());
+
+        /*
+         * A connection that never sends a request, like a speculative one
+         * from a browser, must not hold up the real redirect.
+         */
+        let addr = redirect_uri.trim_start_matches("http://");
+        let _idle = TcpStream::connect(addr).await.unwrap();
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(3),
+            reqwest::get(format!(
+                "{}/?state={}&code=good",
+                redirect_uri, q["state"]
+            )),
+        )
+        .await
+        .expect("redirect held up by idle connection")
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        finish.await.unwrap().unwrap();
+        assert_eq!(auth.refresh_token(), "refresh");
+    }
+
+    #[tokio::test]
+    async fn refresh_shared() {
+        let ts = Server::start(|_| {
+            Response::json(
+                400,
+                &serde_json::json!({
+                    "error": "invalid_grant",
+                    "error_description": "Token has been expired or revoked.",
+                }),
+            )
+            .delay(Duration::from_millis(200))
+        })
+        .await;
+        let auth = GAuth::new(logger(), config(ts.url())).unwrap();
+        auth.set_refresh_token("refresh");
+
+        /*
+         * Callers that arrive while a refresh is in flight must wait for it,
+         * rather than start their own, and must all see its failure.
+         */
+        let tasks = (0..10)
+            .map(|_| {
+                let auth = auth.clone();
+                tokio::spawn(async move { auth.check_refresh().await })
+            })
+            .collect::<Vec<_>>();
+        for t in tasks {
+            assert!(matches!(
+                t.await.unwrap(),
+                Err(AuthError::InvalidGrant(_))
+            ));
+        }
+
+        assert_eq!(ts.requests().len(), 1);
+        assert!(auth.inner.lock().unwrap().refreshing.is_none());
+    }
+
+    /**
+     * A stub for the token and revocation endpoints.  Token requests are
+     * answered after the specified delay, and the server reports that the
+     * token we revoke was already invalid.
+     */
+    async fn revoke_server(delay: Duration) -> Server {
+        Server::start(move |req| {
+            if req.path == "/revoke" {
+                return Response::json(
+                    400,
+                    &serde_json::json!({
+                        "error": "invalid_token",
+                        "error_description": "Token expired or revoked",
+                    }),
+                );
+            }
+
+            Response::json(
+                200,
+                &serde_json::json!({
+                    "access_token": "access",
+                    "expires_in": 3600,
+                    "scope": "https://mail.google.com/",
+                    "token_type": "Bearer",
+                }),
+            )
+            .delay(delay)
+        })
+        .await
+    }
+
+    fn store_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "rgmail-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn revoke() {
+        let ts = revoke_server(Duration::ZERO).await;
+        let path = store_path("revoke");
+        let auth = GAuth::new(logger(), config(ts.url()))
+            .unwrap()
+            .with_token_store(FileTokenStore::new(&path))
+            .unwrap();
+        auth.set_refresh_token("refresh");
+        auth.check_refresh().await.unwrap();
+        assert!(path.exists());
+
+        /*
+         * A token that the server says is already invalid has nothing left
+         * to revoke, so that counts as success.
+         */
+        auth.revoke().await.unwrap();
+        assert!(auth.access_token().is_empty());
+        assert!(auth.refresh_token().is_empty());
+        assert!(!path.exists());
+
+        let reqs = ts.requests();
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(reqs[1].method, "POST");
+        assert_eq!(reqs[1].path, "/revoke");
+        assert_eq!(reqs[1].form()["token"], "refresh");
+    }
+
+    #[tokio::test]
+    async fn revoke_during_refresh() {
+        let ts = revoke_server(Duration::from_millis(300)).await;
+        let path = store_path("revoke-refresh");
+        let auth = GAuth::new(logger(), config(ts.url()))
+            .unwrap()
+            .with_token_store(FileTokenStore::new(&path))
+            .unwrap();
+        auth.set_refresh_token("refresh");
+
+        /*
+         * A refresh that completes after we have revoked our tokens must not
+         * put them back, either in memory or in the store.
+         */
+        let refresh = tokio::spawn({
+            let auth = auth.clone();
+            async move { auth.check_refresh().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        auth.revoke().await.unwrap();
+
+        assert!(matches!(
+            refresh.await.unwrap(),
+            Err(AuthError::NoRefreshToken)
+        ));
+        assert!(auth.access_token().is_empty());
+        assert!(auth.refresh_token().is_empty());
+        assert!(!path.exists());
+        assert_eq!(ts.requests().len(), 2);
+    }
+
+    const SA_KEY: &str = include_str!("testdata/service_account.pem");
+    const SA_PUBLIC_KEY: &str =
+        include_str!("testdata/service_account.pub.pem");
+
+    fn service_account_key(typ: &str, url: &str) -> ServiceAccountKey {
+        serde_json::from_value(serde_json::json!({
+            "type": typ,
+            "client_id": "1234",
+            "client_email": "robot@project.iam.gserviceaccount.com",
+            "private_key_id": "key1",
+            "private_key": SA_KEY,
+            "token_uri": format!("{}/token", url),
+        }))
+        .unwrap()
+    }
+
+    /**
+     * Check the signature and audience of an assertion, and return its
+     * claims.
+     */
+    fn assertion_claims(assertion: &str, aud: &str) -> serde_json::Value {
+        let mut validation = jsonwebtoken::Validation::new(Algorithm::RS256);
+        validation.set_audience(&[aud]);
+        jsonwebtoken::decode(
+            assertion,
+            &jsonwebtoken::DecodingKey::from_rsa_pem(SA_PUBLIC_KEY.as_bytes())
+                .unwrap(),
+            &validation,
+        )
+        .unwrap()
+        .claims
+    }
+
+    #[tokio::test]
+    async fn service_account() {
+        let ts = Server::start(|_| {
+            Response::json(
+                200,
+                &serde_json::json!({
+                    "access_token": "access",
+                    "expires_in": 3600,
+                    "token_type": "Bearer",
+                }),
+            )
+        })
+        .await;
+        let key = service_account_key("service_account", ts.url());
+        assert!(!format!("{:?}", key).contains("PRIVATE KEY"));
+
+        let auth = GAuth::new_service_account(
+            logger(),
+            key,
+            Some("user@example.com"),
+            &[Scope::GmailModify, Scope::Email],
+        )
+        .unwrap();
+        auth.check_refresh().await.unwrap();
+        assert_eq!(auth.access_token(), "access");
+
+        /*
+         * The token endpoint does not list the scopes for an assertion
+         * grant, so we have the ones we asked for.
+         */
+        assert_eq!(
+            auth.granted_scopes(),
+            [Scope::Email, Scope::GmailModify].into_iter().collect()
+        );
+
+        let reqs = ts.requests();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "POST");
+        assert_eq!(reqs[0].path, "/token");
+        let form = reqs[0].form();
+        assert_eq!(form.len(), 2);
+        assert_eq!(form["grant_type"], JWT_BEARER);
+
+        let assertion = &form["assertion"];
+        let header = jsonwebtoken::decode_header(assertion).unwrap();
+        assert_eq!(header.alg, Algorithm::RS256);
+        assert_eq!(header.kid.as_deref(), Some("key1"));
+
+        let token_uri = format!("{}/token", ts.url());
+        let claims = assertion_claims(assertion, &token_uri);
+        assert_eq!(claims["iss"], "robot@project.iam.gserviceaccount.com");
+        assert_eq!(claims["sub"], "user@example.com");
+        assert_eq!(
+            claims["scope"],
+            "https://www.googleapis.com/auth/gmail.modify email"
+        );
+        assert_eq!(claims["aud"], token_uri.as_str());
+        let iat = claims["iat"].as_u64().unwrap();
+        assert_eq!(claims["exp"].as_u64().unwrap(), iat + 3600);
+
+        /*
+         * Without a subject, the service account acts as itself.
+         */
+        let auth = GAuth::new_service_account(
+            logger(),
+            service_account_key("service_account", ts.url()),
+            None,
+            &[Scope::GmailModify],
+        )
+        .unwrap();
+        auth.refresh().await.unwrap();
+        let reqs = ts.requests();
+        assert_eq!(reqs.len(), 2);
+        let claims = assertion_claims(&reqs[1].form()["assertion"], &token_uri);
+        assert!(claims.get("sub").is_none());
+    }
+
+    #[tokio::test]
+    async fn service_account_rejected() {
+        let ts = token_server().await;
+
+        /*
+         * Keys of other types, like those for a user, are refused, as is a
+         * request without any scopes.
+         */
+        assert!(matches!(
+            GAuth::new_service_account(
+                logger(),
+                service_account_key("authorized_user", ts.url()),
+                None,
+                &[Scope::GmailModify],
+            ),
+            Err(AuthError::Config(_))
+        ));
+        assert!(matches!(
+            GAuth::new_service_account(
+                logger(),
+                service_account_key("service_account", ts.url()),
+                None,
+                &[],
+            ),
+            Err(AuthError::Config(_))
+        ));
+
+        /*
+         * A service account cannot be used for interactive authorisation.
+         */
+        let auth = GAuth::new_service_account(
+            logger(),
+            service_account_key("service_account", ts.url()),
+            None,
+            &[Scope::GmailModify],
+        )
+        .unwrap();
+        assert!(matches!(auth.auth_token(false), Err(AuthError::Other(_))));
+        assert!(matches!(
+            auth.exchange("code").await,
+            Err(AuthError::Other(_))
+        ));
+        assert!(matches!(
+            auth.device_start(&AuthOptions::basic(false)).await,
+            Err(AuthError::Other(_))
+        ));
+        assert!(ts.requests().is_empty());
+        assert!(auth.refresh_token().is_empty());
+    }
+
+    #[tokio::test]
+    async fn loopback_denied() {
+        let ts = token_server().await;
+        let auth = GAuth::new(logger(), config(ts.url())).unwrap();
+
+        let lb = auth.loopback(false).await.unwrap();
+        let q = query(lb.url());
+        let redirect_uri = lb.redirect_uri().to_string();
+        let finish = tokio::spawn(lb.finish());
+
+        let res = reqwest::get(format!(
+            "{}/?state={}&error=access_denied",
+            redirect_uri, q["state"]
+        ))
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(matches!(
+            finish.await.unwrap(),
+            Err(AuthError::Redirect { error, .. }) if error == "access_denied"
+        ));
+
+        assert!(ts.requests().is_empty());
+        assert!(auth.access_token().is_empty());
+    }
 }
@@ -3,33 +3,259 @@
  * Copyright 2022 Oxide Computer Company
  */
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
+use futures_util::stream::{self, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 
 use reqwest::header;
 use reqwest::redirect;
-use reqwest::StatusCode;
 use reqwest::{Client, ClientBuilder};
 use serde_aux::prelude::*;
 
-use slog::{debug, trace, Logger};
-
-use anyhow::{bail, Result};
+use slog::{debug, Logger};
+use tokio::io::AsyncWrite;
 
+use super::attachment::{self, RAttachment};
+use super::batch::{self, Batch, BatchRequest};
+use super::error::{check, json, Error, Result};
 use super::gauth::GAuth;
-use super::multipart::multipart_parse;
+use super::retry::RetryPolicy;
+use super::token::TokenSource;
 use super::types::*;
 use super::util::*;
-use super::{history, messages};
+use super::{drafts, history, messages, threads};
 
 #[derive(Clone)]
 pub struct GMailInner {
     pub(crate) log: Logger,
-    pub(crate) auth: GAuth,
+    pub(crate) auth: Arc<dyn TokenSource>,
     pub(crate) client: Client,
+    base_url: reqwest::Url,
+    pub(crate) batch_url: reqwest::Url,
+    retry: RetryPolicy,
+    chunk_size: usize,
+    concurrency: usize,
+}
+
+impl GMailInner {
+    /**
+     * Send a request with a current access token, and check that it was
+     * successful.  The closure is called to build the request again for each
+     * attempt; failures that may be transient are retried as directed by the
+     * retry policy.
+     */
+    pub(crate) async fn execute<F>(&self, build: F) -> Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        self.execute_common(build, true).await
+    }
+
+    /**
+     * Send a request as per execute(), for a request that is not idempotent;
+     * e.g., one that creates something.  The request is made again only if
+     * the first attempt cannot have taken effect, so that a failure after the
+     * server has acted does not result in a duplicate.
+     */
+    pub(crate) async fn execute_once<F>(
+        &self,
+        build: F,
+    ) -> Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        self.execute_common(build, false).await
+    }
+
+    /**
+     * Send a request as per execute() or execute_once(), depending on whether
+     * it is idempotent.
+     */
+    pub(crate) async fn execute_common<F>(
+        &self,
+        build: F,
+        idempotent: bool,
+    ) -> Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let start = Instant::now();
+        let mut attempt = 0;
+
+        loop {
+            let res = match self.auth.token().await {
+                Ok(token) => match build()
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .send()
+                    .await
+                {
+                    Ok(res) => check(res).await,
+                    Err(e) => Err(e.into()),
+                },
+                Err(e) => Err(e),
+            };
+
+            let e = match res {
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+
+            match self.retry.delay(&e, idempotent, attempt, start.elapsed()) {
+                Some(delay) => {
+                    debug!(
+                        self.log,
+                        "retrying in {:?} after attempt {}: {}",
+                        delay,
+                        attempt + 1,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(e),
+            }
+        }
+    }
+
+    /**
+     * Construct the URL for an API path, relative to the base URL; e.g.,
+     * "users/me/profile".
+     */
+    pub(crate) fn url(&self, s: &str) -> String {
+        join(self.base_url.as_str(), s)
+    }
+
+    /**
+     * Construct the request path for an API path within a batch request.  The
+     * batch endpoint expects absolute paths on the same host as the API.
+     */
+    pub(crate) fn batch_path(&self, s: &str) -> String {
+        join(self.base_url.path(), s)
+    }
+}
+
+/**
+ * Configuration for a GMail client.  By default, the client will use the
+ * public Gmail API endpoints, but these may be overridden; e.g., to use a
+ * local mock server in tests.
+ */
+pub struct GMailBuilder {
+    log: Logger,
+    auth: Arc<dyn TokenSource>,
+    base_url: String,
+    batch_url: String,
+    client: Option<Client>,
+    retry: RetryPolicy,
+    chunk_size: usize,
+    concurrency: usize,
+}
+
+impl GMailBuilder {
+    pub fn new<T: TokenSource + 'static>(log: Logger, auth: T) -> GMailBuilder {
+        GMailBuilder {
+            log,
+            auth: Arc::new(auth),
+            base_url: BASE_URL.to_string(),
+            batch_url: BATCH_URL.to_string(),
+            client: None,
+            retry: Default::default(),
+            chunk_size: 50,
+            concurrency: 2,
+        }
+    }
+
+    /**
+     * The base URL for API requests, such as
+     * "https://www.googleapis.com/gmail/v1".
+     */
+    pub fn base_url<S: AsRef<str>>(mut self, url: S) -> GMailBuilder {
+        self.base_url = url.as_ref().to_string();
+        self
+    }
+
+    /**
+     * The URL for batch requests, such as
+     * "https://www.googleapis.com/batch/gmail/v1".
+     */
+    pub fn batch_url<S: AsRef<str>>(mut self, url: S) -> GMailBuilder {
+        self.batch_url = url.as_ref().to_string();
+        self
+    }
+
+    /**
+     * Use this HTTP client, rather than one with our default configuration;
+     * e.g., to set timeouts, a proxy, or additional TLS roots.
+     */
+    pub fn client(mut self, client: Client) -> GMailBuilder {
+        self.client = Some(client);
+        self
+    }
+
+    /**
+     * How to retry requests that fail with rate limiting or other errors that
+     * may be transient.  Use RetryPolicy::none() to disable retries.
+     */
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> GMailBuilder {
+        self.retry = retry;
+        self
+    }
+
+    /**
+     * The number of messages to request in each batch for messages_get() and
+     * similar calls; larger requests are split into several batches.  Gmail
+     * allows at most 100 requests in a batch, but is prone to rate limiting
+     * well before that.
+     */
+    pub fn batch_size(mut self, n: usize) -> GMailBuilder {
+        self.chunk_size = n.clamp(1, MAX_BATCH);
+        self
+    }
+
+    /**
+     * The maximum number of batches that will be in flight at once when a
+     * request has been split into several batches.
+     */
+    pub fn batch_concurrency(mut self, n: usize) -> GMailBuilder {
+        self.concurrency = n.max(1);
+        self
+    }
+
+    pub fn build(self) -> Result<GMail> {
+        let base_url = reqwest::Url::parse(&self.base_url).map_err(|e| {
+            Error::InvalidInput(format!("base URL {:?}: {}", self.base_url, e))
+        })?;
+        let batch_url = reqwest::Url::parse(&self.batch_url).map_err(|e| {
+            Error::InvalidInput(format!(
+                "batch URL {:?}: {}",
+                self.batch_url, e
+            ))
+        })?;
+
+        let client = if let Some(client) = self.client {
+            client
+        } else {
+            ClientBuilder::new()
+                .tcp_keepalive(Duration::from_secs(30))
+                .connect_timeout(Duration::from_secs(30))
+                .redirect(redirect::Policy::none())
+                .build()?
+        };
+
+        Ok(GMail(Arc::new(GMailInner {
+            log: self.log,
+            auth: self.auth,
+            client,
+            base_url,
+            batch_url,
+            retry: self.retry,
+            chunk_size: self.chunk_size,
+            concurrency: self.concurrency,
+        })))
+    }
 }
 
 #[derive(Clone)]
@@ -53,12 +279,61 @@ pub struct Profile {
     pub history_id: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MessageListVisibility {
+    Show,
+    Hide,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LabelListVisibility {
+    LabelShow,
+    LabelShowIfUnread,
+    LabelHide,
+}
+
+/**
+ * The colours of a label, as hex strings such as "#ffffff".  Gmail accepts
+ * only colours from a fixed palette.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelColor {
+    pub text_color: String,
+    pub background_color: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Label {
     id: String,
     name: String,
-    #[serde(rename = "type")]
+    /*
+     * The responses to labels.create and labels.patch may not include the
+     * type.
+     */
+    #[serde(
+        rename = "type",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
     typ: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_list_visibility: Option<MessageListVisibility>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_list_visibility: Option<LabelListVisibility>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<LabelColor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages_total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages_unread: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threads_total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threads_unread: Option<u64>,
 }
 
 impl Label {
@@ -70,14 +345,120 @@ impl Label {
         &self.name
     }
 
+    /**
+     * The type of the label, "system" or "user", or an empty string if the
+     * server did not say.
+     */
     pub fn type_(&self) -> &str {
         &self.typ
     }
+
+    pub fn message_list_visibility(&self) -> Option<MessageListVisibility> {
+        self.message_list_visibility
+    }
+
+    pub fn label_list_visibility(&self) -> Option<LabelListVisibility> {
+        self.label_list_visibility
+    }
+
+    pub fn color(&self) -> Option<&LabelColor> {
+        self.color.as_ref()
+    }
+
+    /*
+     * The message and thread counts are only included when a label is
+     * fetched individually with label_get(), not in the output of
+     * labels_list().
+     */
+
+    pub fn messages_total(&self) -> Option<u64> {
+        self.messages_total
+    }
+
+    pub fn messages_unread(&self) -> Option<u64> {
+        self.messages_unread
+    }
+
+    pub fn threads_total(&self) -> Option<u64> {
+        self.threads_total
+    }
+
+    pub fn threads_unread(&self) -> Option<u64> {
+        self.threads_unread
+    }
+}
+
+/**
+ * The properties to set when creating or updating a label.  Properties that
+ * are not specified are left unchanged by an update.
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_list_visibility: Option<MessageListVisibility>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_list_visibility: Option<LabelListVisibility>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<LabelColor>,
+}
+
+impl LabelOptions {
+    pub fn new() -> LabelOptions {
+        Default::default()
+    }
+
+    pub fn name<S: AsRef<str>>(mut self, name: S) -> LabelOptions {
+        self.name = Some(name.as_ref().to_string());
+        self
+    }
+
+    pub fn message_list_visibility(
+        mut self,
+        v: MessageListVisibility,
+    ) -> LabelOptions {
+        self.message_list_visibility = Some(v);
+        self
+    }
+
+    pub fn label_list_visibility(
+        mut self,
+        v: LabelListVisibility,
+    ) -> LabelOptions {
+        self.label_list_visibility = Some(v);
+        self
+    }
+
+    pub fn color<S: AsRef<str>>(
+        mut self,
+        text: S,
+        background: S,
+    ) -> LabelOptions {
+        self.color = Some(LabelColor {
+            text_color: text.as_ref().to_string(),
+            background_color: background.as_ref().to_string(),
+        });
+        self
+    }
 }
 
 pub trait LabelsHelper {
     fn names(&self) -> Vec<&str>;
     fn id_of(&self, n: &str) -> Option<&str>;
+
+    /**
+     * Gmail does not allow two labels whose names differ only in case, so we
+     * can find a label by name regardless of case.
+     */
+    fn id_of_ignore_case(&self, n: &str) -> Option<&str> {
+        let n = n.to_lowercase();
+        self.names()
+            .into_iter()
+            .find(|name| name.to_lowercase() == n)
+            .and_then(|name| self.id_of(name))
+    }
 }
 
 impl LabelsHelper for Vec<Label> {
@@ -97,6 +478,107 @@ impl LabelsHelper for Vec<Label> {
 
         None
     }
+
+    fn id_of_ignore_case(&self, n: &str) -> Option<&str> {
+        let n = n.to_lowercase();
+        self.iter()
+            .find(|l| l.name.to_lowercase() == n)
+            .map(|l| l.id.as_str())
+    }
+}
+
+/**
+ * The name of the parent of a nested label; e.g., "Customers/Acme" for the
+ * label "Customers/Acme/Invoices".
+ */
+pub fn label_parent_name(name: &str) -> Option<&str> {
+    name.rsplit_once('/').map(|(parent, _)| parent)
+}
+
+/**
+ * Gmail represents nested labels with names separated by slashes, such as
+ * "Customers/Acme/Invoices".  A LabelTree provides navigation of that
+ * hierarchy.  Names are matched without regard to case.
+ */
+pub struct LabelTree<'a> {
+    labels: HashMap<String, &'a Label>,
+}
+
+impl<'a> LabelTree<'a> {
+    pub fn new(labels: &'a [Label]) -> LabelTree<'a> {
+        LabelTree {
+            labels: labels.iter().map(|l| (l.name.to_lowercase(), l)).collect(),
+        }
+    }
+
+    /**
+     * Find a label by name, regardless of case.
+     */
+    pub fn get(&self, name: &str) -> Option<&'a Label> {
+        self.labels.get(&name.to_lowercase()).copied()
+    }
+
+    /**
+     * The parent of a nested label, if the parent exists.
+     */
+    pub fn parent(&self, name: &str) -> Option<&'a Label> {
+        label_parent_name(name).and_then(|p| self.get(p))
+    }
+
+    /**
+     * The labels directly beneath this one in the hierarchy, sorted by name.
+     * Use "" for the labels at the top of the hierarchy.
+     */
+    pub fn children(&self, name: &str) -> Vec<&'a Label> {
+        let name = name.to_lowercase();
+        self.sorted(|n| label_parent_name(n).unwrap_or("") == name)
+    }
+
+    /**
+     * All of the labels beneath this one in the hierarchy, at any depth,
+     * sorted by name so that each label follows its parent.
+     */
+    pub fn descendants(&self, name: &str) -> Vec<&'a Label> {
+        let prefix = format!("{}/", name.to_lowercase());
+        self.sorted(|n| n.starts_with(&prefix))
+    }
+
+    /**
+     * The names of the ancestors of a nested label, from the top of the
+     * hierarchy down, whether or not those labels exist.
+     */
+    pub fn ancestor_names(name: &str) -> Vec<&str> {
+        let mut out: Vec<&str> = Vec::new();
+        let mut n = name;
+        while let Some(p) = label_parent_name(n) {
+            out.push(p);
+            n = p;
+        }
+        out.reverse();
+        out
+    }
+
+    /**
+     * The ancestors of a nested label that do not yet exist, from the top of
+     * the hierarchy down.
+     */
+    pub fn missing_ancestors<'n>(&self, name: &'n str) -> Vec<&'n str> {
+        LabelTree::ancestor_names(name)
+            .into_iter()
+            .filter(|n| self.get(n).is_none())
+            .collect()
+    }
+
+    fn sorted<F: Fn(&str) -> bool>(&self, f: F) -> Vec<&'a Label> {
+        let mut out: Vec<(&String, &'a Label)> = self
+            .labels
+            .iter()
+            .filter(|(n, _)| f(n.as_str()))
+            .map(|(n, l)| (n, *l))
+            .collect();
+        out.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        out.into_iter().map(|(_, l)| l).collect()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -152,10 +634,8 @@ pub struct MessageRaw {
 
 impl MessageRaw {
     pub fn raw(&self) -> Result<Vec<u8>> {
-        Ok(base64::decode_config(
-            self.raw.as_bytes(),
-            base64::URL_SAFE,
-        )?)
+        base64::decode_config(self.raw.as_bytes(), base64::URL_SAFE)
+            .map_err(|e| Error::Decode(format!("raw message: {}", e)))
     }
 }
 
@@ -174,6 +654,257 @@ pub struct MessageMinimal {
     pub internal_date: u64,
 }
 
+/**
+ * A message retrieved in the "full" format, with the MIME structure of the
+ * message parsed into a tree of parts.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageFull {
+    pub id: String,
+    pub thread_id: String,
+    #[serde(default)]
+    pub label_ids: HashSet<String>,
+    #[serde(default)]
+    pub snippet: String,
+    pub size_estimate: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub history_id: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub internal_date: u64,
+    pub payload: MessagePart,
+}
+
+impl MessageFull {
+    /**
+     * The values of all top-level headers with this (case-insensitive) name.
+     */
+    pub fn headers(&self, n: &str) -> Vec<&str> {
+        self.payload.headers(n)
+    }
+
+    pub fn subject(&self) -> &str {
+        self.headers("subject").first().copied().unwrap_or("")
+    }
+
+    pub fn date(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH
+            .checked_add(Duration::from_millis(self.internal_date))
+            .expect("system time add")
+    }
+
+    /**
+     * The first text/plain part that is not an attachment, if there is one.
+     */
+    pub fn text_plain(&self) -> Option<&MessagePart> {
+        self.payload.find_body("text/plain")
+    }
+
+    /**
+     * The first text/html part that is not an attachment, if there is one.
+     */
+    pub fn text_html(&self) -> Option<&MessagePart> {
+        self.payload.find_body("text/html")
+    }
+
+    /**
+     * All of the parts that are attachments, in the order they appear in the
+     * message.
+     */
+    pub fn attachments(&self) -> Vec<&MessagePart> {
+        self.payload
+            .walk()
+            .into_iter()
+            .filter(|p| p.is_attachment())
+            .collect()
+    }
+}
+
+impl MessageId for MessageFull {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+}
+
+/**
+ * A part of a message.  Multipart parts have child parts, and others have a
+ * body, which is either included or must be fetched separately as an
+ * attachment.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagePart {
+    #[serde(default)]
+    pub part_id: String,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub filename: String,
+    #[serde(default)]
+    pub headers: Vec<MessageHeader>,
+    #[serde(default)]
+    pub body: MessagePartBody,
+    #[serde(default)]
+    pub parts: Vec<MessagePart>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagePartBody {
+    #[serde(default)]
+    pub size: u64,
+    pub data: Option<String>,
+    pub attachment_id: Option<String>,
+}
+
+impl MessagePartBody {
+    /**
+     * Decode the body data, if it was included in the response.
+     */
+    pub fn decode(&self) -> Result<Option<Vec<u8>>> {
+        self.data.as_deref().map(decode_url_safe).transpose()
+    }
+}
+
+impl MessagePart {
+    pub fn headers(&self, n: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|mh| mh.name.eq_ignore_ascii_case(n))
+            .map(|mh| mh.value.as_str())
+            .collect()
+    }
+
+    /**
+     * Does this part have the specified MIME type, ignoring case and any
+     * parameters?
+     */
+    pub fn is_type(&self, mime_type: &str) -> bool {
+        self.mime_type
+            .split(';')
+            .next()
+            .map(|t| t.trim().eq_ignore_ascii_case(mime_type))
+            .unwrap_or(false)
+    }
+
+    pub fn is_attachment(&self) -> bool {
+        !self.filename.is_empty()
+    }
+
+    /**
+     * This part and all of its descendants, in depth-first order.
+     */
+    pub fn walk(&self) -> Vec<&MessagePart> {
+        let mut out = vec![self];
+        for p in &self.parts {
+            out.extend(p.walk());
+        }
+        out
+    }
+
+    fn find_body(&self, mime_type: &str) -> Option<&MessagePart> {
+        self.walk()
+            .into_iter()
+            .find(|p| p.is_type(mime_type) && !p.is_attachment())
+    }
+
+    /**
+     * The body of this part as text, if it was included in the response.
+     * Bodies that are not valid UTF-8 are converted lossily.
+     */
+    pub fn text(&self) -> Result<Option<String>> {
+        Ok(self
+            .body
+            .decode()?
+            .map(|b| String::from_utf8_lossy(&b).into_owned()))
+    }
+}
+
+/**
+ * The API uses URL-safe base64 for message data, with or without padding.
+ */
+pub(crate) fn decode_url_safe(data: &str) -> Result<Vec<u8>> {
+    base64::decode_config(
+        data.trim_end_matches('=').as_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    )
+    .map_err(|e| Error::Decode(format!("message data: {}", e)))
+}
+
+/**
+ * A draft message.  Drafts in a list, or returned when a draft is created or
+ * updated, include only the ID, thread and labels of the message; drafts
+ * fetched with draft_get() and similar calls include the message in the
+ * requested format.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Draft<T = MessageSent> {
+    pub id: String,
+    pub message: T,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LabelModify<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ids: Option<&'a [&'a str]>,
+    add_label_ids: &'a [&'a str],
+    remove_label_ids: &'a [&'a str],
+}
+
+#[derive(Debug, Serialize)]
+struct DraftUpload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    message: MessageSend,
+}
+
+/**
+ * A thread (i.e., a conversation).  Threads in a list include only the ID,
+ * snippet and history ID; threads fetched with thread_get() and similar calls
+ * include every message in the thread, in the requested format.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Thread<T = MessageMinimal> {
+    pub id: String,
+    #[serde(default)]
+    pub snippet: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub history_id: u64,
+    #[serde(default = "Vec::new")]
+    pub messages: Vec<T>,
+}
+
+/**
+ * The outcome of messages_batch_delete(), or of a dry run.  Messages are
+ * listed in the order they were requested.
+ */
+#[derive(Debug)]
+pub struct DeleteReport {
+    /**
+     * Was this a dry run, where nothing was actually deleted?
+     */
+    pub dry_run: bool,
+    /**
+     * The messages that were (or would have been) deleted.  Outside of a
+     * dry run, we do not check whether each message exists, so this is
+     * every message we asked to delete.
+     */
+    pub deleted: Vec<String>,
+    /**
+     * The messages that would have been deleted, but do not exist.  This is
+     * only determined for a dry run.
+     */
+    pub missing: Vec<String>,
+    /**
+     * The messages that could not be looked up during a dry run, because
+     * requests for them were still rate limited when the retry policy gave
+     * up.  We cannot say whether these would have been deleted.
+     */
+    pub rate_limited: Vec<String>,
+}
+
 pub trait MessageId {
     fn id(&self) -> &str;
 }
@@ -185,6 +916,22 @@ pub enum MultiResult<T> {
     RateLimit(String),
 }
 
+impl<T: MessageId> MultiResult<T> {
+    /**
+     * The ID of the message to which this result refers.
+     */
+    pub fn id(&self) -> &str {
+        match self {
+            MultiResult::Present(m) => m.id(),
+            MultiResult::Missing(id) | MultiResult::RateLimit(id) => id,
+        }
+    }
+
+    pub fn is_rate_limit(&self) -> bool {
+        matches!(self, MultiResult::RateLimit(_))
+    }
+}
+
 impl MessageMinimal {
     pub fn date(&self) -> SystemTime {
         SystemTime::UNIX_EPOCH
@@ -243,7 +990,7 @@ impl Message {
     pub fn header_or_blank(&self, n: &str) -> &str {
         let h = self.headers(n);
 
-        if let Some(s) = h.get(0) {
+        if let Some(s) = h.first() {
             s
         } else {
             ""
@@ -280,448 +1027,773 @@ impl Message {
 
 impl GMail {
     pub fn new(log: Logger, auth: GAuth) -> GMail {
-        let cb = ClientBuilder::new()
-            .tcp_keepalive(Duration::from_secs(30))
-            .connect_timeout(Duration::from_secs(30))
-            .redirect(redirect::Policy::none());
+        Self::new_with_token_source(log, auth)
+    }
 
-        GMail(Arc::new(GMailInner {
-            log,
-            client: cb.build().expect("build client"),
-            auth,
-        }))
+    /**
+     * Create a client that obtains access tokens from the provided source,
+     * rather than directly from a GAuth.
+     */
+    pub fn new_with_token_source<T: TokenSource + 'static>(
+        log: Logger,
+        auth: T,
+    ) -> GMail {
+        GMailBuilder::new(log, auth).build().expect("build client")
+    }
+
+    pub fn builder<T: TokenSource + 'static>(
+        log: Logger,
+        auth: T,
+    ) -> GMailBuilder {
+        GMailBuilder::new(log, auth)
     }
 
     pub fn history_list(&self, start_at: u64) -> history::HistoryConfig {
         history::HistoryConfig::new(self, start_at)
     }
 
+    /**
+     * Start building a batch of arbitrary API requests, to be sent together in
+     * a single round trip.
+     */
+    pub fn batch(&self) -> Batch {
+        Batch::new(self)
+    }
+
     pub fn messages_list(&self) -> messages::MessagesConfig {
         messages::MessagesConfig::new(self)
     }
 
     pub async fn profile(&self) -> Result<Profile> {
-        let url = bu("users/me/profile");
+        let url = self.url("users/me/profile");
 
-        self.auth.check_refresh().await?;
-
-        let res = self
-            .client
-            .get(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .send()
-            .await?
-            .error_for_status()?;
+        let res = self.execute(|| self.client.get(&url)).await?;
 
-        Ok(res.json().await?)
+        json(res).await
     }
 
     pub async fn message_get_min(&self, id: &str) -> Result<MessageMinimal> {
-        let url = bu(&format!("users/me/messages/{}", id));
-
-        self.auth.check_refresh().await?;
+        let url = self.url(&format!("users/me/messages/{}", id));
 
         let res = self
-            .client
-            .get(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .query(&[("format", "minimal")])
-            .send()
-            .await?
-            .error_for_status()?;
-
-        let t = res.text().await?;
+            .execute(|| self.client.get(&url).query(&[("format", "minimal")]))
+            .await?;
 
-        match serde_json::from_str(&t) {
-            Ok(r) => Ok(r),
-            Err(e) => bail!("parsing response: {}: {}", e, t),
-        }
+        json(res).await
     }
 
     pub async fn messages_get<S: AsRef<str>>(
         &self,
         ids: &[S],
     ) -> Result<Vec<MultiResult<MessageMinimal>>> {
-        self.messages_get_common("minimal", ids).await
+        Ok(self.messages_get_common("minimal", ids).await?.0)
     }
 
     pub async fn messages_get_raw<S: AsRef<str>>(
         &self,
         ids: &[S],
     ) -> Result<Vec<MultiResult<MessageRaw>>> {
-        self.messages_get_common("raw", ids).await
+        Ok(self.messages_get_common("raw", ids).await?.0)
     }
 
-    async fn messages_get_common<T, S: AsRef<str>>(
+    pub async fn messages_get_full<S: AsRef<str>>(
         &self,
-        fmt: &str,
         ids: &[S],
-    ) -> Result<Vec<MultiResult<T>>>
-    where
-        for<'de> T: Deserialize<'de> + MessageId,
-    {
-        let url = bbu();
+    ) -> Result<Vec<MultiResult<MessageFull>>> {
+        Ok(self.messages_get_common("full", ids).await?.0)
+    }
+
+    /**
+     * Like messages_get(), but sub-requests that were rate limited are
+     * submitted again, with backoff according to the retry policy, until they
+     * succeed or the policy gives up.  Any entries still rate limited at that
+     * point are returned as MultiResult::RateLimit.  Results are in the same
+     * order as the requested IDs.
+     */
+    pub async fn messages_get_all<S: AsRef<str>>(
+        &self,
+        ids: &[S],
+    ) -> Result<Vec<MultiResult<MessageMinimal>>> {
+        self.messages_get_redrive("minimal", ids).await
+    }
 
-        self.auth.check_refresh().await?;
+    /**
+     * Like messages_get_raw(), but with rate limited sub-requests submitted
+     * again as for messages_get_all().
+     */
+    pub async fn messages_get_raw_all<S: AsRef<str>>(
+        &self,
+        ids: &[S],
+    ) -> Result<Vec<MultiResult<MessageRaw>>> {
+        self.messages_get_redrive("raw", ids).await
+    }
 
-        let mut body = String::new();
-        let bound = "23121338-972e-11ea-a0c6-c3892af82e36";
+    /**
+     * Like messages_get_full(), but with rate limited sub-requests submitted
+     * again as for messages_get_all().
+     */
+    pub async fn messages_get_full_all<S: AsRef<str>>(
+        &self,
+        ids: &[S],
+    ) -> Result<Vec<MultiResult<MessageFull>>> {
+        self.messages_get_redrive("full", ids).await
+    }
 
-        for (n, id) in ids.iter().enumerate() {
-            body.push_str("--");
-            body.push_str(bound);
-            body.push_str("\r\n");
+    async fn messages_get_redrive<T, S: AsRef<str>>(
+        &self,
+        fmt: &str,
+        ids: &[S],
+    ) -> Result<Vec<MultiResult<T>>>
+    where
+        for<'de> T: Deserialize<'de> + MessageId,
+    {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
 
-            body.push_str("Content-Type: application/http\r\n");
-            body.push_str(&format!("Content-ID: req-{}\r\n", n));
-            body.push_str("\r\n");
+        let start = Instant::now();
+        let mut attempt = 0;
+
+        let mut out: Vec<Option<MultiResult<T>>> =
+            ids.iter().map(|_| None).collect();
+        let mut pending: Vec<usize> = (0..ids.len()).collect();
+
+        loop {
+            let req: Vec<&str> =
+                pending.iter().map(|&i| ids[i].as_ref()).collect();
+            let (res, retry_after) =
+                self.messages_get_common(fmt, &req).await?;
+
+            /*
+             * The results are in the order we requested them, so each one
+             * goes back in the slot for the corresponding pending ID.
+             */
+            let mut limited = Vec::new();
+            for (r, &i) in res.into_iter().zip(pending.iter()) {
+                if r.is_rate_limit() {
+                    limited.push(i);
+                }
+                out[i] = Some(r);
+            }
 
-            body.push_str(&format!(
-                "GET /gmail/v1/users/me/messages/{}?\
-                    format={}\r\n",
-                id.as_ref(),
-                fmt
-            ));
-            body.push_str("\r\n");
+            if limited.is_empty() {
+                break;
+            }
 
-            body.push_str("\r\n");
+            /*
+             * If the server said how long to wait before trying again, we
+             * must wait at least that long.
+             */
+            match self.retry.next_delay(attempt, start.elapsed(), retry_after) {
+                Some(delay) => {
+                    debug!(
+                        self.log,
+                        "{} of {} messages rate limited; retrying in {:?}",
+                        limited.len(),
+                        ids.len(),
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => {
+                    debug!(
+                        self.log,
+                        "giving up on {} rate limited messages",
+                        limited.len()
+                    );
+                    break;
+                }
+            }
+
+            pending = limited;
         }
 
-        body.push_str("--");
-        body.push_str(bound);
-        body.push_str("--\r\n");
+        out.into_iter()
+            .map(|r| {
+                r.ok_or_else(|| {
+                    Error::Batch("message missing from response".into())
+                })
+            })
+            .collect()
+    }
 
-        trace!(self.log, "batch request: {:#?}", body);
+    /**
+     * Fetch messages in batches.  As well as the results, returns the longest
+     * period that the server asked us to wait before retrying any of the
+     * rate limited requests.
+     */
+    async fn messages_get_common<T, S: AsRef<str>>(
+        &self,
+        fmt: &str,
+        ids: &[S],
+    ) -> Result<(Vec<MultiResult<T>>, Option<Duration>)>
+    where
+        for<'de> T: Deserialize<'de> + MessageId,
+    {
+        /*
+         * Split the request into batches of the configured size, and keep a
+         * bounded number of those in flight at once.  The results from each
+         * batch are merged in the order the batches were submitted.
+         */
+        let chunks: Vec<(Vec<MultiResult<T>>, Option<Duration>)> =
+            stream::iter(ids.chunks(self.chunk_size))
+                .map(|chunk| self.messages_get_batch(fmt, chunk))
+                .buffered(self.concurrency)
+                .try_collect()
+                .await?;
 
-        let buf = body.as_bytes().to_vec();
+        let retry_after = chunks.iter().filter_map(|(_, ra)| *ra).max();
+        let out = chunks.into_iter().flat_map(|(res, _)| res).collect();
 
-        let res = self
-            .client
-            .post(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .header(
-                header::CONTENT_TYPE,
-                format!(
-                    "multipart/mixed; \
-                boundary={}",
-                    bound
-                ),
-            )
-            .body(buf)
-            .send()
-            .await?
-            .error_for_status()?;
-
-        let rbnd = if let Some(ct) = res.headers().get(header::CONTENT_TYPE) {
-            let ct: mime::Mime = ct.to_str()?.parse()?;
-            if let Some(b) = ct.get_param("boundary") {
-                b.to_string()
-            } else {
-                bail!("content type missing boundary");
+        Ok((out, retry_after))
+    }
+
+    async fn messages_get_batch<T, S: AsRef<str>>(
+        &self,
+        fmt: &str,
+        ids: &[S],
+    ) -> Result<(Vec<MultiResult<T>>, Option<Duration>)>
+    where
+        for<'de> T: Deserialize<'de> + MessageId,
+    {
+        let requests: Vec<BatchRequest> = ids
+            .iter()
+            .map(|id| {
+                BatchRequest::get(format!("users/me/messages/{}", id.as_ref()))
+                    .query("format", fmt)
+            })
+            .collect();
+
+        /*
+         * The responses are in the same order as the requests, so we can
+         * match each one to the message ID that we asked for.
+         */
+        let parts = batch::send(self, &requests).await?;
+
+        let mut out: Vec<MultiResult<T>> = Vec::with_capacity(ids.len());
+        let mut retry_after: Option<Duration> = None;
+
+        for (p, id) in parts.into_iter().zip(ids.iter().map(|id| id.as_ref())) {
+            if p.status() == 404 {
+                /*
+                 * Report that this message was not found.
+                 */
+                out.push(MultiResult::Missing(id.to_string()));
+                continue;
+            } else if let Some(e) = p.error() {
+                if e.is_rate_limit() {
+                    retry_after = retry_after.max(p.retry_after());
+                    out.push(MultiResult::RateLimit(id.to_string()));
+                    continue;
+                }
+
+                let b = String::from_utf8_lossy(p.body());
+                debug!(self.log, "response for {}: {}", id, b);
+                return Err(e);
             }
-        } else {
-            bail!("content type missing from response");
-        };
-        trace!(self.log, "boundary: {:#?}", rbnd);
-
-        let x = res.bytes().await?;
-
-        let mp = match multipart_parse(&x, rbnd.as_bytes()) {
-            Ok(mp) => mp,
-            Err(e) => {
-                let report = if x.len() < 200 { &x } else { &x[..200] };
-                debug!(
-                    self.log,
-                    "response: {:#?}",
-                    String::from_utf8_lossy(report)
-                );
-                bail!("response multipart error: (boundary {:?}) {}", rbnd, e);
+
+            let msg: T = p.json()?;
+            if msg.id() != id {
+                return Err(Error::Batch(format!(
+                    "response for message {} contained message {}",
+                    id,
+                    msg.id()
+                )));
             }
+            out.push(MultiResult::Present(msg));
+        }
+
+        Ok((out, retry_after))
+    }
+
+    pub async fn message_get(&self, id: &str) -> Result<Message> {
+        let url = self.url(&format!("users/me/messages/{}", id));
+
+        let res = self
+            .execute(|| self.client.get(&url).query(&[("format", "metadata")]))
+            .await?;
+
+        json(res).await
+    }
+
+    /**
+     * Fetch a message in the "full" format, including the MIME structure and
+     * the bodies of parts that are not attachments.
+     */
+    pub async fn message_get_full(&self, id: &str) -> Result<MessageFull> {
+        let url = self.url(&format!("users/me/messages/{}", id));
+
+        let res = self
+            .execute(|| self.client.get(&url).query(&[("format", "full")]))
+            .await?;
+
+        json(res).await
+    }
+
+    pub async fn message_get_raw(&self, id: &str) -> Result<Vec<u8>> {
+        let url = self.url(&format!("users/me/messages/{}", id));
+
+        let res = self
+            .execute(|| self.client.get(&url).query(&[("format", "raw")]))
+            .await?;
+
+        let mr: MessageRaw = json(res).await?;
+
+        mr.raw()
+    }
+
+    /**
+     * Add and remove labels on a message in a single operation.  Returns the
+     * labels that are now applied to the message.
+     */
+    pub async fn message_modify(
+        &self,
+        id: &str,
+        add: &[&str],
+        remove: &[&str],
+    ) -> Result<HashSet<String>> {
+        let url = self.url(&format!("users/me/messages/{}/modify", id));
+
+        let body = LabelModify {
+            ids: None,
+            add_label_ids: add,
+            remove_label_ids: remove,
         };
 
-        let mut out: Vec<MultiResult<T>> = Vec::new();
+        let res = self.execute(|| self.client.post(&url).json(&body)).await?;
 
-        for p in &mp.parts {
-            let report = if p.body.len() < 200 {
-                &p.body
-            } else {
-                &p.body[..200]
-            };
-            trace!(
-                self.log,
-                "process part: {:#?} {:#?}",
-                p.headers,
-                String::from_utf8_lossy(report)
-            );
+        let m: MessageSent = json(res).await?;
 
-            if let Some(ct) = p.headers.get("content-type") {
-                let ct: mime::Mime = ct.parse()?;
-                match (ct.type_(), ct.subtype().as_str()) {
-                    (mime::APPLICATION, "http") => (),
-                    ct => bail!("response part had wrong type: {:?}", ct),
-                };
-            } else {
-                bail!("content type missing from response part");
-            }
+        Ok(m.label_ids)
+    }
 
-            let id: &str = if let Some(cid) = p.headers.get("content-id") {
-                if let Some(n) = cid.strip_prefix("response-req-") {
-                    let n: usize = n.parse()?;
-                    if n < ids.len() {
-                        ids[n].as_ref()
-                    } else {
-                        bail!("content id invalid in response part");
-                    }
-                } else {
-                    bail!("content id invalid in response part");
-                }
-            } else {
-                bail!("content type missing from response part");
+    /**
+     * Add and remove labels on many messages.  Gmail accepts at most 1000
+     * messages in each request, so larger sets are split into several
+     * requests; if one of those fails, the earlier ones will already have
+     * taken effect.
+     */
+    pub async fn messages_batch_modify<S: AsRef<str>>(
+        &self,
+        ids: &[S],
+        add: &[&str],
+        remove: &[&str],
+    ) -> Result<()> {
+        let url = self.url("users/me/messages/batchModify");
+
+        for chunk in ids.chunks(MAX_BATCH_MODIFY) {
+            let chunk: Vec<&str> = chunk.iter().map(|id| id.as_ref()).collect();
+
+            let body = LabelModify {
+                ids: Some(&chunk),
+                add_label_ids: add,
+                remove_label_ids: remove,
             };
 
-            let mut headers = [httparse::EMPTY_HEADER; 32];
-            let mut parser = httparse::Response::new(&mut headers);
-            let res = parser.parse(&p.body)?;
+            debug!(self.log, "modifying labels on {} messages", chunk.len());
 
-            if res.is_complete() {
-                let c = res.unwrap();
-                let status = parser.code.unwrap();
+            self.execute(|| self.client.post(&url).json(&body)).await?;
+        }
 
-                let mut ct: Option<String> = None;
-                let mut cl: Option<usize> = None;
-                for h in &headers {
-                    trace!(self.log, "part header: {:?}", h);
-                    if h.name.to_ascii_lowercase() == "content-type" {
-                        ct = Some(String::from_utf8(h.value.to_vec())?);
-                    }
-                    if h.name.to_ascii_lowercase() == "content-length" {
-                        cl =
-                            Some(String::from_utf8(h.value.to_vec())?.parse()?);
-                    }
-                }
+        Ok(())
+    }
 
-                if ct.is_none() {
-                    debug!(
-                        self.log,
-                        "response: {:#?}",
-                        String::from_utf8_lossy(report)
-                    );
-                    bail!("headers missing from response part response");
-                }
+    /**
+     * Move a message to the trash.
+     */
+    pub async fn message_trash(&self, id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/messages/{}/trash", id));
 
-                #[allow(dead_code)]
-                #[derive(Deserialize, Debug)]
-                struct Eee {
-                    domain: String,
-                    reason: String,
-                    message: String,
-                }
+        self.execute(|| self.client.post(&url)).await?;
 
-                #[allow(dead_code)]
-                #[derive(Deserialize, Debug)]
-                struct Ee {
-                    errors: Vec<Eee>,
-                    code: u32,
-                    message: String,
-                }
+        Ok(())
+    }
 
-                #[derive(Deserialize)]
-                struct E {
-                    error: Ee,
-                }
+    /**
+     * Remove a message from the trash.
+     */
+    pub async fn message_untrash(&self, id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/messages/{}/untrash", id));
 
-                if status == 404 {
-                    /*
-                     * Report that this message was not found.
-                     */
-                    out.push(MultiResult::Missing(id.to_string()));
-                    continue;
-                } else if status == 429 {
-                    out.push(MultiResult::RateLimit(id.to_string()));
-                    continue;
-                } else if status != 200 {
-                    if status == 403 {
-                        match serde_json::from_slice::<E>(&p.body[c..]) {
-                            Ok(e) => {
-                                debug!(
-                                    self.log,
-                                    "403 error: {}", e.error.message
-                                );
-
-                                let mut ok = false;
-                                for ee in &e.error.errors {
-                                    if ee.domain == "usageLimits"
-                                        && (ee.reason
-                                            == "userRateLimitExceeded"
-                                            || ee.reason == "rateLimitExceeded")
-                                    {
-                                        out.push(MultiResult::RateLimit(
-                                            id.to_string(),
-                                        ));
-                                        ok = true;
-                                        break;
-                                    }
-                                }
-
-                                if ok {
-                                    continue;
-                                }
-
-                                bail!(
-                                    "{} error for {}: {:?}",
-                                    status,
-                                    id,
-                                    e.error
-                                );
-                            }
-                            Err(e) => {
-                                let b = String::from_utf8_lossy(&p.body[c..]);
-                                debug!(self.log, "response: {}", b);
-                                bail!("could not parse 403: {}", e);
-                            }
-                        }
-                    }
+        self.execute(|| self.client.post(&url)).await?;
 
-                    let b = String::from_utf8_lossy(&p.body[c..]);
-                    debug!(self.log, "response: {}", b);
-                    bail!(
-                        "inner response part had wrong status: {} for {}",
-                        status,
-                        id
-                    );
-                }
+        Ok(())
+    }
 
-                let ct: mime::Mime = ct.unwrap().parse()?;
-                match (ct.type_(), ct.subtype()) {
-                    (mime::APPLICATION, mime::JSON) => (),
-                    ct => {
-                        bail!("response part response had wrong type: {:?}", ct)
-                    }
-                };
+    /**
+     * Delete a message immediately and permanently, without moving it to the
+     * trash first.
+     */
+    pub async fn message_delete(&self, id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/messages/{}", id));
 
-                if let Some(cl) = cl {
-                    if cl != p.body.len() - c {
-                        bail!(
-                            "response part body len {} not what we \
-                            expected (i.e., {})",
-                            p.body.len() - c,
-                            cl
-                        );
-                    }
-                }
+        self.execute(|| self.client.delete(&url)).await?;
 
-                out.push(MultiResult::Present(serde_json::from_slice(
-                    &p.body[c..],
-                )?));
-            } else {
-                bail!("response part response incomplete");
+        Ok(())
+    }
+
+    /**
+     * Delete many messages immediately and permanently.  Gmail accepts at most
+     * 1000 messages in each request, so larger sets are split into several
+     * requests; if one of those fails, the earlier ones will already have
+     * taken effect.
+     */
+    pub async fn messages_batch_delete<S: AsRef<str>>(
+        &self,
+        ids: &[S],
+    ) -> Result<DeleteReport> {
+        let url = self.url("users/me/messages/batchDelete");
+
+        #[derive(Serialize)]
+        struct BD<'a> {
+            ids: &'a [&'a str],
+        }
+
+        for chunk in ids.chunks(MAX_BATCH_MODIFY) {
+            let chunk: Vec<&str> = chunk.iter().map(|id| id.as_ref()).collect();
+
+            debug!(self.log, "deleting {} messages", chunk.len());
+
+            self.execute(|| self.client.post(&url).json(&BD { ids: &chunk }))
+                .await?;
+        }
+
+        Ok(DeleteReport {
+            dry_run: false,
+            deleted: ids.iter().map(|id| id.as_ref().to_string()).collect(),
+            missing: Vec::new(),
+            rate_limited: Vec::new(),
+        })
+    }
+
+    /**
+     * Report what messages_batch_delete() would do, without deleting
+     * anything.  Each message is looked up so that the report can
+     * distinguish the messages that would be deleted from those that do not
+     * exist.  Messages that are still rate limited once the retry policy gives
+     * up are listed in the report as such, rather than failing the dry run.
+     */
+    pub async fn messages_batch_delete_dry_run<S: AsRef<str>>(
+        &self,
+        ids: &[S],
+    ) -> Result<DeleteReport> {
+        let mut deleted = Vec::new();
+        let mut missing = Vec::new();
+        let mut rate_limited = Vec::new();
+
+        for r in self.messages_get_all(ids).await? {
+            match r {
+                MultiResult::Present(m) => deleted.push(m.id),
+                MultiResult::Missing(id) => missing.push(id),
+                MultiResult::RateLimit(id) => {
+                    debug!(self.log, "dry run: rate limited on {}", id);
+                    rate_limited.push(id);
+                }
             }
         }
 
-        if out.len() != ids.len() {
-            bail!("did not get enough messages");
+        debug!(
+            self.log,
+            "dry run: {} to delete, {} missing, {} rate limited",
+            deleted.len(),
+            missing.len(),
+            rate_limited.len()
+        );
+
+        Ok(DeleteReport {
+            dry_run: true,
+            deleted,
+            missing,
+            rate_limited,
+        })
+    }
+
+    /**
+     * Fetch an attachment, such as one listed by MessageFull::attachments(),
+     * and return its contents.
+     */
+    pub async fn attachment_get(
+        &self,
+        message_id: &str,
+        attachment_id: &str,
+    ) -> Result<Vec<u8>> {
+        let url = self.url(&format!(
+            "users/me/messages/{}/attachments/{}",
+            message_id, attachment_id
+        ));
+
+        let res = self.execute(|| self.client.get(&url)).await?;
+
+        let ra: RAttachment = json(res).await?;
+        let data = decode_url_safe(&ra.data)?;
+        if data.len() as u64 != ra.size {
+            return Err(Error::Decode(format!(
+                "attachment was {} bytes, but expected {} bytes",
+                data.len(),
+                ra.size
+            )));
         }
 
+        Ok(data)
+    }
+
+    /**
+     * Fetch an attachment and write its contents as they arrive, without
+     * holding the whole attachment in memory.  If a maximum size is
+     * specified, the download fails once the attachment exceeds that size.
+     * Returns the size of the attachment.  Note that if the download fails
+     * part way through, some data may already have been written.
+     */
+    pub async fn attachment_write<W>(
+        &self,
+        message_id: &str,
+        attachment_id: &str,
+        w: &mut W,
+        max_size: Option<u64>,
+    ) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let url = self.url(&format!(
+            "users/me/messages/{}/attachments/{}",
+            message_id, attachment_id
+        ));
+
+        let res = self.execute(|| self.client.get(&url)).await?;
+
+        attachment::write_response(res, w, max_size).await
+    }
+
+    /**
+     * Fetch an attachment and save it to a file, as for attachment_write().
+     * The attachment is written to a temporary file in the same directory,
+     * which is renamed into place only once the download is complete and the
+     * size has been checked.
+     */
+    pub async fn attachment_save<P: AsRef<Path>>(
+        &self,
+        message_id: &str,
+        attachment_id: &str,
+        path: P,
+        max_size: Option<u64>,
+    ) -> Result<u64> {
+        let path = path.as_ref();
+
+        let tmp = temp_path(path).ok_or_else(|| {
+            Error::InvalidInput(format!("attachment file {:?}", path))
+        })?;
+
         /*
-         * Final (and obviously not optimal) checks for completeness:
+         * The temporary file must be new, so that we are the only writer;
+         * if we cannot create it, there is nothing of ours to clean up.
          */
-        for o in &out {
-            let mut found = false;
-            for i in ids {
-                match o {
-                    MultiResult::Present(msg) => {
-                        if msg.id() == i.as_ref() {
-                            found = true;
-                            break;
-                        }
-                    }
-                    MultiResult::Missing(id) | MultiResult::RateLimit(id) => {
-                        if id.as_str() == i.as_ref() {
-                            found = true;
-                            break;
-                        }
-                    }
-                }
-            }
-            if !found {
-                bail!("message missing from response");
-            }
+        let mut f = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)
+            .await?;
+
+        let res = async {
+            let size = self
+                .attachment_write(message_id, attachment_id, &mut f, max_size)
+                .await?;
+            f.sync_all().await?;
+            drop(f);
+            tokio::fs::rename(&tmp, path).await?;
+            Ok(size)
         }
+        .await;
 
-        Ok(out)
+        if res.is_err() {
+            tokio::fs::remove_file(&tmp).await.ok();
+        }
+
+        res
     }
 
-    pub async fn message_get(&self, id: &str) -> Result<Message> {
-        let url = bu(&format!("users/me/messages/{}", id));
+    pub fn drafts_list(&self) -> drafts::DraftsConfig {
+        drafts::DraftsConfig::new(self)
+    }
 
-        self.auth.check_refresh().await?;
+    /**
+     * Create a draft from a raw RFC 5322 message.
+     */
+    pub async fn draft_create(&self, raw: &[u8]) -> Result<Draft> {
+        let url = self.url("users/me/drafts");
+
+        let du = DraftUpload {
+            id: None,
+            message: MessageSend::new(raw)?,
+        };
 
         let res = self
-            .client
-            .get(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .query(&[("format", "metadata")])
-            .send()
-            .await?
-            .error_for_status()?;
+            .execute_once(|| self.client.post(&url).json(&du))
+            .await?;
 
-        Ok(res.json().await?)
+        json(res).await
     }
 
-    pub async fn message_get_raw(&self, id: &str) -> Result<Vec<u8>> {
-        let url = bu(&format!("users/me/messages/{}", id));
+    /**
+     * Replace the contents of an existing draft with a raw RFC 5322 message.
+     */
+    pub async fn draft_update(&self, id: &str, raw: &[u8]) -> Result<Draft> {
+        let url = self.url(&format!("users/me/drafts/{}", id));
+
+        let du = DraftUpload {
+            id: Some(id.to_string()),
+            message: MessageSend::new(raw)?,
+        };
 
-        self.auth.check_refresh().await?;
+        let res = self.execute(|| self.client.put(&url).json(&du)).await?;
+
+        json(res).await
+    }
+
+    async fn draft_get_common<T>(&self, id: &str, fmt: &str) -> Result<Draft<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let url = self.url(&format!("users/me/drafts/{}", id));
 
         let res = self
-            .client
-            .get(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .query(&[("format", "raw")])
-            .send()
-            .await?
-            .error_for_status()?;
+            .execute(|| self.client.get(&url).query(&[("format", fmt)]))
+            .await?;
+
+        json(res).await
+    }
 
-        let mr: MessageRaw = res.json().await?;
+    pub async fn draft_get_min(
+        &self,
+        id: &str,
+    ) -> Result<Draft<MessageMinimal>> {
+        self.draft_get_common(id, "minimal").await
+    }
 
-        Ok(base64::decode_config(mr.raw.as_bytes(), base64::URL_SAFE)?)
+    pub async fn draft_get(&self, id: &str) -> Result<Draft<Message>> {
+        self.draft_get_common(id, "metadata").await
     }
 
-    pub async fn message_send(&self, raw: &[u8]) -> Result<MessageSent> {
-        let url = bu("users/me/messages/send");
+    pub async fn draft_get_full(&self, id: &str) -> Result<Draft<MessageFull>> {
+        self.draft_get_common(id, "full").await
+    }
+
+    pub async fn draft_get_raw(&self, id: &str) -> Result<Draft<MessageRaw>> {
+        self.draft_get_common(id, "raw").await
+    }
+
+    /**
+     * Send an existing draft.  The draft is removed once it has been sent.
+     */
+    pub async fn draft_send(&self, id: &str) -> Result<MessageSent> {
+        let url = self.url("users/me/drafts/send");
+
+        #[derive(Serialize)]
+        struct DS<'a> {
+            id: &'a str,
+        }
+
+        let res = self
+            .execute_once(|| self.client.post(&url).json(&DS { id }))
+            .await?;
+
+        json(res).await
+    }
 
-        self.auth.check_refresh().await?;
+    /**
+     * Delete a draft immediately and permanently, without sending it.
+     */
+    pub async fn draft_delete(&self, id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/drafts/{}", id));
+
+        self.execute(|| self.client.delete(&url)).await?;
+
+        Ok(())
+    }
+
+    pub async fn message_send(&self, raw: &[u8]) -> Result<MessageSent> {
+        let url = self.url("users/me/messages/send");
 
         let ms = MessageSend::new(raw)?;
 
         let res = self
-            .client
-            .post(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .json(&ms)
-            .send()
-            .await?
-            .error_for_status()?;
+            .execute_once(|| self.client.post(&url).json(&ms))
+            .await?;
+
+        json(res).await
+    }
+
+    pub fn threads_list(&self) -> threads::ThreadsConfig {
+        threads::ThreadsConfig::new(self)
+    }
+
+    async fn thread_get_common<T>(
+        &self,
+        id: &str,
+        fmt: &str,
+    ) -> Result<Thread<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let url = self.url(&format!("users/me/threads/{}", id));
+
+        let res = self
+            .execute(|| self.client.get(&url).query(&[("format", fmt)]))
+            .await?;
+
+        json(res).await
+    }
+
+    pub async fn thread_get_min(
+        &self,
+        id: &str,
+    ) -> Result<Thread<MessageMinimal>> {
+        self.thread_get_common(id, "minimal").await
+    }
+
+    pub async fn thread_get(&self, id: &str) -> Result<Thread<Message>> {
+        self.thread_get_common(id, "metadata").await
+    }
+
+    pub async fn thread_get_full(
+        &self,
+        id: &str,
+    ) -> Result<Thread<MessageFull>> {
+        self.thread_get_common(id, "full").await
+    }
+
+    /**
+     * Add and remove labels on every message in a thread.  Returns the labels
+     * that are now applied to any message in the thread.
+     */
+    pub async fn thread_modify(
+        &self,
+        thread_id: &str,
+        add: &[&str],
+        remove: &[&str],
+    ) -> Result<HashSet<String>> {
+        let res = self.thread_modify_common(thread_id, add, remove).await?;
+
+        let t: Thread<MessageSent> = json(res).await?;
+
+        Ok(t.messages.into_iter().flat_map(|m| m.label_ids).collect())
+    }
+
+    async fn thread_modify_common(
+        &self,
+        thread_id: &str,
+        add: &[&str],
+        remove: &[&str],
+    ) -> Result<reqwest::Response> {
+        let url = self.url(&format!("users/me/threads/{}/modify", thread_id));
+
+        let body = LabelModify {
+            ids: None,
+            add_label_ids: add,
+            remove_label_ids: remove,
+        };
 
-        Ok(res.json().await?)
+        self.execute(|| self.client.post(&url).json(&body)).await
     }
 
     pub async fn thread_remove_label(
@@ -729,56 +1801,1521 @@ impl GMail {
         thread_id: &str,
         label: &str,
     ) -> Result<()> {
-        let url = bu(&format!("users/me/threads/{}/modify", thread_id));
+        /*
+         * The caller has no use for the updated thread, so we need not
+         * insist that we can make sense of it.
+         */
+        self.thread_modify_common(thread_id, &[], &[label]).await?;
 
-        #[derive(Serialize)]
-        #[serde(rename_all = "camelCase")]
-        struct RB<'a> {
-            remove_label_ids: Vec<&'a str>,
-        }
+        Ok(())
+    }
 
-        self.auth.check_refresh().await?;
+    /**
+     * Move a thread to the trash.
+     */
+    pub async fn thread_trash(&self, thread_id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/threads/{}/trash", thread_id));
 
-        self.client
-            .post(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .json(&RB {
-                remove_label_ids: vec![label],
-            })
-            .send()
-            .await?
-            .error_for_status()?;
+        self.execute(|| self.client.post(&url)).await?;
+
+        Ok(())
+    }
+
+    /**
+     * Remove a thread from the trash.
+     */
+    pub async fn thread_untrash(&self, thread_id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/threads/{}/untrash", thread_id));
+
+        self.execute(|| self.client.post(&url)).await?;
+
+        Ok(())
+    }
+
+    /**
+     * Delete a thread immediately and permanently, without moving it to the
+     * trash first.
+     */
+    pub async fn thread_delete(&self, thread_id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/threads/{}", thread_id));
+
+        self.execute(|| self.client.delete(&url)).await?;
 
         Ok(())
     }
 
     pub async fn labels_list(&self) -> Result<Vec<Label>> {
-        let url = bu("users/me/labels");
+        let url = self.url("users/me/labels");
+
+        let res = self.execute(|| self.client.get(&url)).await?;
 
-        self.auth.check_refresh().await?;
+        #[derive(Deserialize)]
+        struct RLabels {
+            labels: Vec<Label>,
+        }
+
+        let o: RLabels = json(res).await?;
+
+        Ok(o.labels)
+    }
+
+    /**
+     * Fetch a label, including the message and thread counts.
+     */
+    pub async fn label_get(&self, id: &str) -> Result<Label> {
+        let url = self.url(&format!("users/me/labels/{}", id));
+
+        let res = self.execute(|| self.client.get(&url)).await?;
+
+        json(res).await
+    }
+
+    pub async fn label_create(
+        &self,
+        name: &str,
+        opts: LabelOptions,
+    ) -> Result<Label> {
+        let url = self.url("users/me/labels");
+
+        let opts = opts.name(name);
 
         let res = self
-            .client
-            .get(&url)
-            .header(
-                header::AUTHORIZATION,
-                format!("Bearer {}", self.auth.access_token()),
-            )
-            .send()
+            .execute_once(|| self.client.post(&url).json(&opts))
             .await?;
 
-        if res.status() != StatusCode::OK {
-            bail!("oddball response: {:#?}", res);
+        json(res).await
+    }
+
+    /**
+     * Create a nested label, such as "Customers/Acme/Invoices", first
+     * creating any ancestors (e.g., "Customers" and "Customers/Acme") that do
+     * not already exist.  The options apply only to the requested label; any
+     * ancestors are created with the default options.  If the requested label
+     * already exists, perhaps with different case, it is returned as it is
+     * and the options are not applied.
+     */
+    pub async fn label_create_nested(
+        &self,
+        name: &str,
+        opts: LabelOptions,
+    ) -> Result<Label> {
+        let mut labels = self.labels_list().await?;
+        let tree = LabelTree::new(&labels);
+
+        /*
+         * If some ancestors already exist, perhaps with different case, use
+         * the existing name for the deepest of those as the prefix for the
+         * labels we create.
+         */
+        let name = LabelTree::ancestor_names(name)
+            .into_iter()
+            .rev()
+            .find_map(|a| tree.get(a).map(|l| (a, l)))
+            .map(|(a, l)| format!("{}{}", l.name(), &name[a.len()..]))
+            .unwrap_or_else(|| name.to_string());
+
+        /*
+         * Names are matched without regard to case, so this finds the label
+         * whether it was requested with its existing case or not.
+         */
+        if let Some(i) = tree
+            .get(&name)
+            .and_then(|l| labels.iter().position(|o| o.id == l.id))
+        {
+            debug!(self.log, "label {:?} already exists", name);
+            return Ok(labels.swap_remove(i));
+        }
+
+        for a in tree.missing_ancestors(&name) {
+            debug!(self.log, "creating missing ancestor label {:?}", a);
+            self.label_create(a, LabelOptions::new()).await?;
+        }
+
+        self.label_create(&name, opts).await
+    }
+
+    /**
+     * Update the specified properties of a label; e.g., to rename it, or to
+     * change its colour.
+     */
+    pub async fn label_patch(
+        &self,
+        id: &str,
+        opts: LabelOptions,
+    ) -> Result<Label> {
+        let url = self.url(&format!("users/me/labels/{}", id));
+
+        let res = self.execute(|| self.client.patch(&url).json(&opts)).await?;
+
+        json(res).await
+    }
+
+    /**
+     * Delete a label, removing it from any messages and threads to which it
+     * is applied.
+     */
+    pub async fn label_delete(&self, id: &str) -> Result<()> {
+        let url = self.url(&format!("users/me/labels/{}", id));
+
+        self.execute(|| self.client.delete(&url)).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::testutil::{logger, Response, Server};
+    use crate::token::StaticToken;
+
+    /**
+     * A stub for both the API and the batch endpoint, which answers the
+     * profile request and a batch of one request.
+     */
+    async fn stub() -> Server {
+        Server::start(|req| {
+            if req.path.starts_with("/batch/") {
+                Response::new(200)
+                    .header("Content-Type", "multipart/mixed; boundary=b")
+                    .body(
+                        "--b\r\n\
+                        Content-Type: application/http\r\n\
+                        Content-ID: response-req-0\r\n\
+                        \r\n\
+                        HTTP/1.1 200 OK\r\n\
+                        Content-Type: application/json\r\n\
+                        \r\n\
+                        {}\r\n\
+                        --b--\r\n",
+                    )
+            } else {
+                Response::json(
+                    200,
+                    &serde_json::json!({
+                        "emailAddress": "user@example.com",
+                        "messagesTotal": 1,
+                        "threadsTotal": 1,
+                        "historyId": "1",
+                    }),
+                )
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn base_url_paths() {
+        let srv = stub().await;
+        let prefixes = ["", "", "/gmail/v1", "/gmail/v1"];
+        let bases = [
+            srv.url().to_string(),
+            format!("{}/", srv.url()),
+            format!("{}/gmail/v1", srv.url()),
+            format!("{}/gmail/v1/", srv.url()),
+        ];
+
+        for (base, prefix) in bases.iter().zip(prefixes) {
+            let gm = GMailBuilder::new(logger(), StaticToken::new("token"))
+                .base_url(base)
+                .batch_url(format!("{}/batch/gmail/v1", srv.url()))
+                .build()
+                .unwrap();
+
+            assert_eq!(
+                gm.url("users/me/profile"),
+                format!("{}{}/users/me/profile", srv.url(), prefix)
+            );
+            assert_eq!(
+                gm.batch_path("users/me/labels/INBOX"),
+                format!("{}/users/me/labels/INBOX", prefix)
+            );
+
+            gm.profile().await.unwrap();
+            let res = gm
+                .batch()
+                .request(BatchRequest::get("users/me/labels/INBOX"))
+                .send()
+                .await
+                .unwrap();
+            res[0].check().unwrap();
+        }
+
+        let reqs = srv.requests();
+        assert_eq!(reqs.len(), 2 * prefixes.len());
+        for (pair, prefix) in reqs.chunks(2).zip(prefixes) {
+            assert_eq!(pair[0].method, "GET");
+            assert_eq!(pair[0].path, format!("{}/users/me/profile", prefix));
+            assert_eq!(pair[0].headers["authorization"], "Bearer token");
+
+            assert_eq!(pair[1].method, "POST");
+            assert_eq!(pair[1].path, "/batch/gmail/v1");
+            let body = String::from_utf8_lossy(&pair[1].body);
+            let line = format!("\r\nGET {}/users/me/labels/INBOX\r\n", prefix);
+            assert!(body.contains(&line), "batch body: {}", body);
+        }
+
+        for (base, batch) in [("gmail/v1", srv.url()), (srv.url(), "")] {
+            let e = GMailBuilder::new(logger(), StaticToken::new("token"))
+                .base_url(base)
+                .batch_url(batch)
+                .build();
+            assert!(matches!(e, Err(Error::InvalidInput(_))));
+        }
+    }
+
+    /**
+     * A stub that fails the first request with the specified response, and
+     * then answers with a label.
+     */
+    async fn flaky(status: u16, reason: &'static str) -> Server {
+        let failed = AtomicBool::new(false);
+        Server::start(move |_| {
+            if !failed.swap(true, Ordering::SeqCst) {
+                Response::json(
+                    status,
+                    &serde_json::json!({
+                        "error": {
+                            "code": status,
+                            "message": "try again",
+                            "errors": [{
+                                "domain": "usageLimits",
+                                "reason": reason,
+                                "message": "try again",
+                            }],
+                        },
+                    }),
+                )
+            } else {
+                Response::json(
+                    200,
+                    &serde_json::json!({
+                        "id": "Label_1",
+                        "name": "Acme",
+                        "type": "user",
+                    }),
+                )
+            }
+        })
+        .await
+    }
+
+    fn client(srv: &Server) -> GMail {
+        GMailBuilder::new(logger(), StaticToken::new("token"))
+            .base_url(srv.url())
+            .retry_policy(
+                RetryPolicy::new().initial_backoff(Duration::from_millis(1)),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retry_idempotent() {
+        let srv = flaky(503, "backendError").await;
+        let gm = client(&srv);
+        assert_eq!(gm.label_get("Label_1").await.unwrap().id(), "Label_1");
+        assert_eq!(srv.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_create() {
+        /*
+         * A server error leaves us unable to tell whether the label was
+         * created, so we must not try again.
+         */
+        let srv = flaky(503, "backendError").await;
+        let gm = client(&srv);
+        let e = gm.label_create("Acme", LabelOptions::new()).await;
+        assert!(matches!(e, Err(Error::Http { status: 503, .. })));
+        assert_eq!(srv.requests().len(), 1);
+
+        /*
+         * If we were rate limited, the request was not acted upon.
+         */
+        for (status, reason) in
+            [(429, "rateLimitExceeded"), (403, "userRateLimitExceeded")]
+        {
+            let srv = flaky(status, reason).await;
+            let gm = client(&srv);
+            gm.label_create("Acme", LabelOptions::new()).await.unwrap();
+            assert_eq!(srv.requests().len(), 2);
+        }
+    }
+
+    /**
+     * A stub batch endpoint for message requests, which answers the parts of
+     * each batch in reverse order.  The message "gone" does not exist, and
+     * requests for "slow" are rate limited.  The first request for "later"
+     * is rate limited, with a request to wait a second before trying again.
+     */
+    async fn messages_stub() -> Server {
+        let later = AtomicBool::new(false);
+        Server::start(move |req| {
+            let body = String::from_utf8_lossy(&req.body);
+            let ids = body
+                .lines()
+                .filter_map(|l| l.strip_prefix("GET /users/me/messages/"))
+                .map(|l| l.split('?').next().unwrap().to_string())
+                .collect::<Vec<_>>();
+
+            let mut out = String::new();
+            for (n, id) in ids.iter().enumerate().rev() {
+                let mut headers = "Content-Type: application/json\r\n";
+                let (status, body) = match id.as_str() {
+                    "gone" => ("404 Not Found", String::new()),
+                    "slow" => ("429 Too Many Requests", String::new()),
+                    "later" if !later.swap(true, Ordering::SeqCst) => {
+                        headers = "Retry-After: 1\r\n";
+                        ("429 Too Many Requests", String::new())
+                    }
+                    id => (
+                        "200 OK",
+                        serde_json::json!({
+                            "id": id,
+                            "threadId": "t",
+                            "snippet": "",
+                            "sizeEstimate": 1,
+                            "historyId": "1",
+                            "internalDate": "0",
+                        })
+                        .to_string(),
+                    ),
+                };
+                out.push_str(&format!(
+                    "--b\r\n\
+                    Content-Type: application/http\r\n\
+                    Content-ID: response-req-{}\r\n\
+                    \r\n\
+                    HTTP/1.1 {}\r\n\
+                    {}\
+                    \r\n\
+                    {}\r\n",
+                    n, status, headers, body
+                ));
+            }
+            out.push_str("--b--\r\n");
+
+            Response::new(200)
+                .header("Content-Type", "multipart/mixed; boundary=b")
+                .body(out)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn messages_get_order() {
+        let srv = messages_stub().await;
+        let gm = GMailBuilder::new(logger(), StaticToken::new("token"))
+            .base_url(srv.url())
+            .batch_url(srv.url())
+            .batch_size(2)
+            .build()
+            .unwrap();
+
+        let ids = ["a", "gone", "b", "slow", "c"];
+        let res = gm.messages_get(&ids).await.unwrap();
+
+        assert_eq!(res.iter().map(MultiResult::id).collect::<Vec<_>>(), ids);
+        assert!(matches!(&res[0], MultiResult::Present(m) if m.id == "a"));
+        assert!(matches!(&res[1], MultiResult::Missing(id) if id == "gone"));
+        assert!(matches!(&res[2], MultiResult::Present(m) if m.id == "b"));
+        assert!(matches!(&res[3], MultiResult::RateLimit(id) if id == "slow"));
+        assert!(matches!(&res[4], MultiResult::Present(m) if m.id == "c"));
+        assert_eq!(srv.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn messages_get_retry_after() {
+        let srv = messages_stub().await;
+        let gm = GMailBuilder::new(logger(), StaticToken::new("token"))
+            .base_url(srv.url())
+            .batch_url(srv.url())
+            .retry_policy(
+                RetryPolicy::new().initial_backoff(Duration::from_millis(1)),
+            )
+            .build()
+            .unwrap();
+
+        /*
+         * The request that was rate limited must not be made again until
+         * the server said we could.
+         */
+        let start = Instant::now();
+        let res = gm.messages_get_all(&["a", "later"]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        assert!(matches!(&res[0], MultiResult::Present(m) if m.id == "a"));
+        assert!(matches!(&res[1], MultiResult::Present(m) if m.id == "later"));
+        assert_eq!(srv.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn delete_dry_run() {
+        let srv = messages_stub().await;
+        let gm = GMailBuilder::new(logger(), StaticToken::new("token"))
+            .base_url(srv.url())
+            .batch_url(srv.url())
+            .retry_policy(
+                RetryPolicy::new()
+                    .initial_backoff(Duration::from_millis(1))
+                    .max_retries(2),
+            )
+            .build()
+            .unwrap();
+
+        let r = gm
+            .messages_batch_delete_dry_run(&["a", "slow", "gone", "b"])
+            .await
+            .unwrap();
+
+        assert!(r.dry_run);
+        assert_eq!(r.deleted, ["a", "b"]);
+        assert_eq!(r.missing, ["gone"]);
+        assert_eq!(r.rate_limited, ["slow"]);
+        assert_eq!(srv.requests().len(), 3);
+    }
+
+    /**
+     * A stub that lists the labels "CUSTOMERS" and "Customers/Acme", and
+     * answers a create request with a new label of the requested name.  As
+     * with Gmail, the response to a create request does not include the
+     * type.
+     */
+    async fn labels_stub() -> Server {
+        Server::start(|req| {
+            if req.method == "POST" {
+                let v: serde_json::Value =
+                    serde_json::from_slice(&req.body).unwrap();
+                return Response::json(
+                    200,
+                    &serde_json::json!({
+                        "id": "Label_new",
+                        "name": v["name"],
+                    }),
+                );
+            }
+
+            Response::json(
+                200,
+                &serde_json::json!({
+                    "labels": [
+                        {
+                            "id": "Label_1",
+                            "name": "CUSTOMERS",
+                            "type": "user",
+                        },
+                        {
+                            "id": "Label_2",
+                            "name": "Customers/Acme",
+                            "type": "user",
+                        },
+                    ],
+                }),
+            )
+        })
+        .await
+    }
+
+    fn label_list(names: &[&str]) -> Vec<Label> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(n, name)| {
+                serde_json::from_value(serde_json::json!({
+                    "id": format!("Label_{}", n),
+                    "name": name,
+                    "type": "user",
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn label_names(labels: Vec<&Label>) -> Vec<&str> {
+        labels.into_iter().map(Label::name).collect()
+    }
+
+    #[test]
+    fn label_tree() {
+        let labels = label_list(&[
+            "Customers/Acme/Invoices",
+            "INBOX",
+            "CUSTOMERS",
+            "Customers/Acme",
+            "Customers/Widgets",
+            "Orphan/Child",
+        ]);
+        let tree = LabelTree::new(&labels);
+
+        assert_eq!(tree.get("customers/acme").unwrap().id(), "Label_3");
+        assert!(tree.get("Customers/Nobody").is_none());
+
+        assert_eq!(
+            tree.parent("customers/acme/invoices").unwrap().name(),
+            "Customers/Acme"
+        );
+        assert_eq!(tree.parent("Customers/Acme").unwrap().name(), "CUSTOMERS");
+        assert!(tree.parent("INBOX").is_none());
+        assert!(tree.parent("Orphan/Child").is_none());
+
+        assert_eq!(label_names(tree.children("")), ["CUSTOMERS", "INBOX"]);
+        assert_eq!(
+            label_names(tree.children("Customers")),
+            ["Customers/Acme", "Customers/Widgets"]
+        );
+        assert!(tree.children("INBOX").is_empty());
+
+        assert_eq!(
+            label_names(tree.descendants("customers")),
+            [
+                "Customers/Acme",
+                "Customers/Acme/Invoices",
+                "Customers/Widgets"
+            ]
+        );
+        assert!(tree.descendants("Customers/Acme/Invoices").is_empty());
+
+        assert_eq!(LabelTree::ancestor_names("A/B/C/D"), ["A", "A/B", "A/B/C"]);
+        assert!(LabelTree::ancestor_names("A").is_empty());
+        assert_eq!(
+            tree.missing_ancestors("customers/acme/Invoices/2022/Q1"),
+            ["customers/acme/Invoices/2022"]
+        );
+        assert_eq!(
+            tree.missing_ancestors("Orphan/Child/Grandchild"),
+            ["Orphan"]
+        );
+        assert!(tree.missing_ancestors("INBOX").is_empty());
+    }
+
+    #[test]
+    fn labels_helper() {
+        let labels = label_list(&["INBOX", "Customers/Acme"]);
+        assert_eq!(labels.names(), ["Customers/Acme", "INBOX"]);
+        assert_eq!(labels.id_of("Customers/Acme"), Some("Label_1"));
+        assert!(labels.id_of("customers/acme").is_none());
+        assert_eq!(labels.id_of_ignore_case("customers/ACME"), Some("Label_1"));
+
+        /*
+         * Implementations that predate id_of_ignore_case() get it in terms
+         * of the other methods.
+         */
+        struct Names;
+        impl LabelsHelper for Names {
+            fn names(&self) -> Vec<&str> {
+                vec!["INBOX", "Customers/Acme"]
+            }
+
+            fn id_of(&self, n: &str) -> Option<&str> {
+                (n == "Customers/Acme").then_some("Label_1")
+            }
+        }
+        assert_eq!(Names.id_of_ignore_case("CUSTOMERS/acme"), Some("Label_1"));
+        assert!(Names.id_of_ignore_case("Customers").is_none());
+    }
+
+    #[tokio::test]
+    async fn label_create_nested_existing() {
+        for name in ["Customers/Acme", "customers/ACME", "CUSTOMERS/acme"] {
+            let srv = labels_stub().await;
+            let gm = client(&srv);
+            let l = gm
+                .label_create_nested(name, LabelOptions::new())
+                .await
+                .unwrap();
+            assert_eq!(l.id(), "Label_2");
+            assert_eq!(l.name(), "Customers/Acme");
+            assert_eq!(srv.requests().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn label_create_nested_leaf() {
+        let srv = labels_stub().await;
+        let gm = client(&srv);
+        let l = gm
+            .label_create_nested("customers/acme/Invoices", LabelOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(l.id(), "Label_new");
+        assert_eq!(l.name(), "Customers/Acme/Invoices");
+
+        let reqs = srv.requests();
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(reqs[1].method, "POST");
+    }
+
+    #[tokio::test]
+    async fn label_properties() {
+        let srv = Server::start(|req| match req.method.as_str() {
+            /*
+             * The responses to create and patch requests include the
+             * properties that were set, but not the type or the counts.
+             */
+            "POST" | "PATCH" => Response::json(
+                200,
+                &serde_json::json!({
+                    "id": "Label_7",
+                    "name": "Acme",
+                    "messageListVisibility": "hide",
+                    "labelListVisibility": "labelShowIfUnread",
+                    "color": {
+                        "textColor": "#000000",
+                        "backgroundColor": "#fad165",
+                    },
+                }),
+            ),
+            _ => Response::json(
+                200,
+                &serde_json::json!({
+                    "id": "Label_7",
+                    "name": "Acme",
+                    "type": "user",
+                    "messageListVisibility": "hide",
+                    "labelListVisibility": "labelShowIfUnread",
+                    "color": {
+                        "textColor": "#000000",
+                        "backgroundColor": "#fad165",
+                    },
+                    "messagesTotal": 12,
+                    "messagesUnread": 3,
+                    "threadsTotal": 10,
+                    "threadsUnread": 2,
+                }),
+            ),
+        })
+        .await;
+        let gm = client(&srv);
+
+        let opts = LabelOptions::new()
+            .message_list_visibility(MessageListVisibility::Hide)
+            .label_list_visibility(LabelListVisibility::LabelShowIfUnread)
+            .color("#000000", "#fad165");
+        let color = LabelColor {
+            text_color: "#000000".into(),
+            background_color: "#fad165".into(),
+        };
+
+        for l in [
+            gm.label_create("Acme", opts.clone()).await.unwrap(),
+            gm.label_patch("Label_7", opts).await.unwrap(),
+        ] {
+            assert_eq!(l.id(), "Label_7");
+            assert_eq!(l.name(), "Acme");
+            assert_eq!(l.type_(), "");
+            assert_eq!(
+                l.message_list_visibility(),
+                Some(MessageListVisibility::Hide)
+            );
+            assert_eq!(
+                l.label_list_visibility(),
+                Some(LabelListVisibility::LabelShowIfUnread)
+            );
+            assert_eq!(l.color(), Some(&color));
+            assert_eq!(l.messages_total(), None);
+        }
+
+        let l = gm.label_get("Label_7").await.unwrap();
+        assert_eq!(l.type_(), "user");
+        assert_eq!(
+            l.message_list_visibility(),
+            Some(MessageListVisibility::Hide)
+        );
+        assert_eq!(
+            l.label_list_visibility(),
+            Some(LabelListVisibility::LabelShowIfUnread)
+        );
+        assert_eq!(l.color(), Some(&color));
+        assert_eq!(l.messages_total(), Some(12));
+        assert_eq!(l.messages_unread(), Some(3));
+        assert_eq!(l.threads_total(), Some(10));
+        assert_eq!(l.threads_unread(), Some(2));
+
+        let reqs = srv.requests();
+        assert_eq!(reqs.len(), 3);
+        assert_eq!(reqs[0].path, "/users/me/labels");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&reqs[0].body).unwrap(),
+            serde_json::json!({
+                "name": "Acme",
+                "messageListVisibility": "hide",
+                "labelListVisibility": "labelShowIfUnread",
+                "color": {
+                    "textColor": "#000000",
+                    "backgroundColor": "#fad165",
+                },
+            })
+        );
+        assert_eq!(reqs[1].method, "PATCH");
+        assert_eq!(reqs[1].path, "/users/me/labels/Label_7");
+        assert_eq!(reqs[2].method, "GET");
+        assert_eq!(reqs[2].path, "/users/me/labels/Label_7");
+    }
+
+    /**
+     * A message with a text attachment, a body with plain text and HTML
+     * alternatives, and a PDF attachment, as returned in the "full" format.
+     */
+    fn message_full(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "threadId": "t1",
+            "labelIds": ["INBOX"],
+            "snippet": "hi",
+            "sizeEstimate": 2048,
+            "historyId": "7",
+            "internalDate": "1650000000000",
+            "payload": {
+                "partId": "",
+                "mimeType": "multipart/mixed",
+                "filename": "",
+                "headers": [
+                    { "name": "Subject", "value": "Invoice" },
+                    { "name": "To", "value": "a@example.com" },
+                    { "name": "to", "value": "b@example.com" },
+                ],
+                "body": { "size": 0 },
+                "parts": [
+                    {
+                        "partId": "0",
+                        "mimeType": "text/plain",
+                        "filename": "notes.txt",
+                        "body": { "size": 5, "attachmentId": "att0" },
+                    },
+                    {
+                        "partId": "1",
+                        "mimeType": "multipart/alternative",
+                        "filename": "",
+                        "body": { "size": 0 },
+                        "parts": [
+                            {
+                                "partId": "1.0",
+                                "mimeType": "Text/Plain; charset=\"UTF-8\"",
+                                "filename": "",
+                                "body": { "size": 2, "data": "aGk=" },
+                            },
+                            {
+                                "partId": "1.1",
+                                "mimeType": "text/html; charset=UTF-8",
+                                "filename": "",
+                                "body": {
+                                    "size": 12,
+                                    "data": "PGI-aGVsbG88L2I-",
+                                },
+                            },
+                        ],
+                    },
+                    {
+                        "partId": "2",
+                        "mimeType": "application/pdf",
+                        "filename": "invoice.pdf",
+                        "body": { "size": 1234, "attachmentId": "att2" },
+                    },
+                ],
+            },
+        })
+    }
+
+    #[test]
+    fn message_parts() {
+        let m: MessageFull =
+            serde_json::from_value(message_full("m1")).unwrap();
+
+        assert_eq!(m.subject(), "Invoice");
+        assert_eq!(m.headers("TO"), ["a@example.com", "b@example.com"]);
+        assert_eq!(
+            m.date(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1650000000)
+        );
+
+        assert_eq!(
+            m.payload
+                .walk()
+                .into_iter()
+                .map(|p| p.part_id.as_str())
+                .collect::<Vec<_>>(),
+            ["", "0", "1", "1.0", "1.1", "2"]
+        );
+
+        /*
+         * The text attachment comes first, but is not the body.
+         */
+        let plain = m.text_plain().unwrap();
+        assert_eq!(plain.part_id, "1.0");
+        assert!(plain.is_type("text/plain"));
+        assert!(!plain.is_type("text/html"));
+        assert_eq!(plain.text().unwrap().as_deref(), Some("hi"));
+
+        let html = m.text_html().unwrap();
+        assert_eq!(html.part_id, "1.1");
+        assert_eq!(html.text().unwrap().as_deref(), Some("<b>hello</b>"));
+
+        assert!(m.payload.is_type("multipart/mixed"));
+        assert!(!m.payload.is_type("multipart"));
+
+        let att = m.attachments();
+        assert_eq!(
+            att.iter().map(|p| p.filename.as_str()).collect::<Vec<_>>(),
+            ["notes.txt", "invoice.pdf"]
+        );
+        assert_eq!(att[1].body.attachment_id.as_deref(), Some("att2"));
+        assert_eq!(att[1].body.size, 1234);
+        assert!(att[1].body.decode().unwrap().is_none());
+    }
+
+    #[test]
+    fn message_part_body_decode() {
+        for data in ["aGk=", "aGk"] {
+            let body = MessagePartBody {
+                size: 2,
+                data: Some(data.to_string()),
+                attachment_id: None,
+            };
+            assert_eq!(body.decode().unwrap().unwrap(), b"hi");
+        }
+
+        let body = MessagePartBody {
+            size: 3,
+            data: Some("a+b/".to_string()),
+            attachment_id: None,
+        };
+        assert!(matches!(body.decode(), Err(Error::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn messages_get_full() {
+        let srv = Server::start(|req| {
+            let body = String::from_utf8_lossy(&req.body);
+            let mut out = String::new();
+            for (n, l) in body
+                .lines()
+                .filter_map(|l| l.strip_prefix("GET /users/me/messages/"))
+                .enumerate()
+            {
+                let id = l.split('?').next().unwrap();
+                out.push_str(&format!(
+                    "--b\r\n\
+                    Content-Type: application/http\r\n\
+                    Content-ID: response-req-{}\r\n\
+                    \r\n\
+                    HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/json\r\n\
+                    \r\n\
+                    {}\r\n",
+                    n,
+                    message_full(id)
+                ));
+            }
+            out.push_str("--b--\r\n");
+
+            Response::new(200)
+                .header("Content-Type", "multipart/mixed; boundary=b")
+                .body(out)
+        })
+        .await;
+        let gm = GMailBuilder::new(logger(), StaticToken::new("token"))
+            .base_url(srv.url())
+            .batch_url(srv.url())
+            .build()
+            .unwrap();
+
+        let res = gm.messages_get_full(&["m1", "m2"]).await.unwrap();
+        assert_eq!(res.len(), 2);
+        for (r, id) in res.iter().zip(["m1", "m2"]) {
+            let m = match r {
+                MultiResult::Present(m) => m,
+                r => panic!("unexpected result {:?}", r),
+            };
+            assert_eq!(m.id, id);
+            assert_eq!(m.text_plain().unwrap().text().unwrap().unwrap(), "hi");
+            assert_eq!(m.attachments().len(), 2);
+        }
+
+        let reqs = srv.requests();
+        assert_eq!(reqs.len(), 1);
+        let body = String::from_utf8_lossy(&reqs[0].body);
+        assert!(body.contains("GET /users/me/messages/m1?format=full"));
+        assert!(body.contains("GET /users/me/messages/m2?format=full"));
+    }
+
+    /**
+     * A stub for the drafts endpoints.  The draft "d1" contains the message
+     * from message_full(), with the raw form included for good measure.
+     * Drafts are listed two to a page.
+     */
+    async fn drafts_stub() -> Server {
+        Server::start(|req| {
+            let sent = serde_json::json!({
+                "id": "m1",
+                "threadId": "t1",
+                "labelIds": ["DRAFT"],
+            });
+            let (path, query) =
+                req.path.split_once('?').unwrap_or((&req.path, ""));
+
+            match (req.method.as_str(), path) {
+                ("GET", "/users/me/drafts") => {
+                    let (ids, next) = if query.contains("pageToken=p2") {
+                        (vec!["d3"], None)
+                    } else {
+                        (vec!["d1", "d2"], Some("p2"))
+                    };
+                    Response::json(
+                        200,
+                        &serde_json::json!({
+                            "drafts": ids
+                                .into_iter()
+                                .map(|id| serde_json::json!({
+                                    "id": id,
+                                    "message": sent,
+                                }))
+                                .collect::<Vec<_>>(),
+                            "nextPageToken": next,
+                            "resultSizeEstimate": 3,
+                        }),
+                    )
+                }
+                ("GET", _) => {
+                    let mut m = message_full("m1");
+                    m["raw"] = "aGk=".into();
+                    Response::json(
+                        200,
+                        &serde_json::json!({ "id": "d1", "message": m }),
+                    )
+                }
+                ("POST", "/users/me/drafts/send") => Response::json(200, &sent),
+                ("DELETE", _) => Response::new(204),
+                _ => Response::json(
+                    200,
+                    &serde_json::json!({ "id": "d1", "message": sent }),
+                ),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn drafts() {
+        let srv = drafts_stub().await;
+        let gm = client(&srv);
+
+        let d = gm.draft_create(b"Subject: hi\r\n\r\nhi").await.unwrap();
+        assert_eq!(d.id, "d1");
+        assert_eq!(d.message.id, "m1");
+        let d = gm
+            .draft_update("d1", b"Subject: hello\r\n\r\nhello")
+            .await
+            .unwrap();
+        assert_eq!(d.id, "d1");
+
+        assert_eq!(gm.draft_get_min("d1").await.unwrap().message.id, "m1");
+        assert_eq!(gm.draft_get("d1").await.unwrap().message.history_id, "7");
+        assert_eq!(
+            gm.draft_get_full("d1").await.unwrap().message.subject(),
+            "Invoice"
+        );
+        assert_eq!(
+            gm.draft_get_raw("d1").await.unwrap().message.raw().unwrap(),
+            b"hi"
+        );
+
+        let m = gm.draft_send("d1").await.unwrap();
+        assert_eq!(m.label_ids, labels(&["DRAFT"]));
+        gm.draft_delete("d1").await.unwrap();
+
+        let ids = gm
+            .drafts_list()
+            .batch_size(2)
+            .start()
+            .map_ok(|d| d.id)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(ids, ["d1", "d2", "d3"]);
+
+        let reqs = srv.requests();
+        assert_eq!(
+            reqs.iter()
+                .map(|r| (r.method.as_str(), r.path.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("POST", "/users/me/drafts"),
+                ("PUT", "/users/me/drafts/d1"),
+                ("GET", "/users/me/drafts/d1?format=minimal"),
+                ("GET", "/users/me/drafts/d1?format=metadata"),
+                ("GET", "/users/me/drafts/d1?format=full"),
+                ("GET", "/users/me/drafts/d1?format=raw"),
+                ("POST", "/users/me/drafts/send"),
+                ("DELETE", "/users/me/drafts/d1"),
+                ("GET", "/users/me/drafts?maxResults=2"),
+                ("GET", "/users/me/drafts?pageToken=p2&maxResults=2"),
+            ]
+        );
+
+        /*
+         * The message is sent as URL-safe base64 in the "raw" property.
+         */
+        assert_eq!(
+            body(&reqs[0]),
+            serde_json::json!({
+                "message": {
+                    "raw": base64::encode_config(
+                        b"Subject: hi\r\n\r\nhi",
+                        base64::URL_SAFE,
+                    ),
+                },
+            })
+        );
+        assert_eq!(
+            body(&reqs[1]),
+            serde_json::json!({
+                "id": "d1",
+                "message": {
+                    "raw": base64::encode_config(
+                        b"Subject: hello\r\n\r\nhello",
+                        base64::URL_SAFE,
+                    ),
+                },
+            })
+        );
+        assert_eq!(body(&reqs[6]), serde_json::json!({ "id": "d1" }));
+    }
+
+    /**
+     * A stub for the threads endpoints.  The thread "t1" contains two copies
+     * of the message from message_full().  Threads are listed two to a page.
+     */
+    async fn threads_stub() -> Server {
+        Server::start(|req| {
+            let (path, query) =
+                req.path.split_once('?').unwrap_or((&req.path, ""));
+
+            match (req.method.as_str(), path) {
+                ("GET", "/users/me/threads") => {
+                    let (ids, next) = if query.contains("pageToken=p2") {
+                        (vec!["t3"], None)
+                    } else {
+                        (vec!["t1", "t2"], Some("p2"))
+                    };
+                    Response::json(
+                        200,
+                        &serde_json::json!({
+                            "threads": ids
+                                .into_iter()
+                                .map(|id| serde_json::json!({
+                                    "id": id,
+                                    "snippet": "hi",
+                                    "historyId": "7",
+                                }))
+                                .collect::<Vec<_>>(),
+                            "nextPageToken": next,
+                            "resultSizeEstimate": 3,
+                        }),
+                    )
+                }
+                ("DELETE", _) => Response::new(204),
+                _ => Response::json(
+                    200,
+                    &serde_json::json!({
+                        "id": "t1",
+                        "historyId": "7",
+                        "messages": [message_full("m1"), message_full("m2")],
+                    }),
+                ),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn threads() {
+        let srv = threads_stub().await;
+        let gm = client(&srv);
+
+        let t = gm.thread_get_min("t1").await.unwrap();
+        assert_eq!(t.id, "t1");
+        assert_eq!(t.history_id, 7);
+        assert_eq!(
+            t.messages.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(),
+            ["m1", "m2"]
+        );
+        assert_eq!(gm.thread_get("t1").await.unwrap().messages.len(), 2);
+        let t = gm.thread_get_full("t1").await.unwrap();
+        assert_eq!(t.messages[1].subject(), "Invoice");
+
+        gm.thread_trash("t1").await.unwrap();
+        gm.thread_untrash("t1").await.unwrap();
+        gm.thread_delete("t1").await.unwrap();
+
+        let ids = gm
+            .threads_list()
+            .label_add("INBOX")
+            .batch_size(2)
+            .start()
+            .map_ok(|t| t.id)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(ids, ["t1", "t2", "t3"]);
+
+        let reqs = srv.requests();
+        assert_eq!(
+            reqs.iter()
+                .map(|r| (r.method.as_str(), r.path.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("GET", "/users/me/threads/t1?format=minimal"),
+                ("GET", "/users/me/threads/t1?format=metadata"),
+                ("GET", "/users/me/threads/t1?format=full"),
+                ("POST", "/users/me/threads/t1/trash"),
+                ("POST", "/users/me/threads/t1/untrash"),
+                ("DELETE", "/users/me/threads/t1"),
+                ("GET", "/users/me/threads?labelIds=INBOX&maxResults=2"),
+                (
+                    "GET",
+                    "/users/me/threads?labelIds=INBOX&pageToken=p2\
+                    &maxResults=2"
+                ),
+            ]
+        );
+    }
+
+    /**
+     * A stub for requests that modify messages and threads.  A modified
+     * message has the labels "INBOX" and "Label_1", and a modified thread has
+     * two messages with different labels, except for the thread "odd", for
+     * which the response is not a thread at all.  Batch requests get an
+     * empty response, as they do from Gmail.
+     */
+    async fn modify_stub() -> Server {
+        Server::start(|req| {
+            let path = req.path.as_str();
+            if path.ends_with("/batchModify") || path.ends_with("/batchDelete")
+            {
+                Response::new(204)
+            } else if path == "/users/me/threads/odd/modify" {
+                Response::json(200, &serde_json::json!({}))
+            } else if path.starts_with("/users/me/threads/") {
+                Response::json(
+                    200,
+                    &serde_json::json!({
+                        "id": "t1",
+                        "historyId": "5",
+                        "messages": [
+                            {
+                                "id": "m1",
+                                "threadId": "t1",
+                                "labelIds": ["INBOX", "Label_1"],
+                            },
+                            {
+                                "id": "m2",
+                                "threadId": "t1",
+                                "labelIds": ["Label_1", "Label_2"],
+                            },
+                        ],
+                    }),
+                )
+            } else if req.method == "DELETE" {
+                Response::new(204)
+            } else {
+                Response::json(
+                    200,
+                    &serde_json::json!({
+                        "id": "m1",
+                        "threadId": "t1",
+                        "labelIds": ["INBOX", "Label_1"],
+                    }),
+                )
+            }
+        })
+        .await
+    }
+
+    fn body(req: &crate::testutil::Request) -> serde_json::Value {
+        serde_json::from_slice(&req.body).unwrap()
+    }
+
+    fn labels(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn message_modify() {
+        let srv = modify_stub().await;
+        let gm = client(&srv);
+
+        let l = gm
+            .message_modify("m1", &["Label_1"], &["UNREAD"])
+            .await
+            .unwrap();
+        assert_eq!(l, labels(&["INBOX", "Label_1"]));
+
+        let reqs = srv.requests();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "POST");
+        assert_eq!(reqs[0].path, "/users/me/messages/m1/modify");
+        assert_eq!(
+            body(&reqs[0]),
+            serde_json::json!({
+                "addLabelIds": ["Label_1"],
+                "removeLabelIds": ["UNREAD"],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_batch_modify() {
+        /*
+         * Up to 1000 messages fit in one request, and the rest go in
+         * another.
+         */
+        for (count, sizes) in [(1000, vec![1000]), (1001, vec![1000, 1])] {
+            let srv = modify_stub().await;
+            let gm = client(&srv);
+
+            let ids = (0..count).map(|n| format!("m{}", n)).collect::<Vec<_>>();
+            gm.messages_batch_modify(&ids, &["Label_1"], &["INBOX"])
+                .await
+                .unwrap();
+
+            let reqs = srv.requests();
+            assert_eq!(
+                reqs.iter()
+                    .map(|r| body(r)["ids"].as_array().unwrap().len())
+                    .collect::<Vec<_>>(),
+                sizes
+            );
+            let mut sent = Vec::new();
+            for r in &reqs {
+                assert_eq!(r.method, "POST");
+                assert_eq!(r.path, "/users/me/messages/batchModify");
+                let b = body(r);
+                assert_eq!(b["addLabelIds"], serde_json::json!(["Label_1"]));
+                assert_eq!(b["removeLabelIds"], serde_json::json!(["INBOX"]));
+                sent.extend(
+                    b["ids"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|id| id.as_str().unwrap().to_string()),
+                );
+            }
+            assert_eq!(sent, ids);
         }
+    }
+
+    #[tokio::test]
+    async fn thread_modify() {
+        let srv = modify_stub().await;
+        let gm = client(&srv);
 
-        let o: serde_json::Value = res.json().await?;
+        /*
+         * The labels of a thread are those of all of its messages.
+         */
+        let l = gm
+            .thread_modify("t1", &["Label_2"], &["UNREAD"])
+            .await
+            .unwrap();
+        assert_eq!(l, labels(&["INBOX", "Label_1", "Label_2"]));
 
-        match o.get("labels") {
-            None => bail!("missing \"labels\" in response"),
-            Some(l) => Ok(serde_json::from_value(l.to_owned())?),
+        /*
+         * Removing a label does not depend on the contents of the response.
+         */
+        gm.thread_remove_label("odd", "INBOX").await.unwrap();
+        assert!(matches!(
+            gm.thread_modify("odd", &[], &["INBOX"]).await,
+            Err(Error::Decode(_))
+        ));
+
+        let reqs = srv.requests();
+        assert_eq!(reqs.len(), 3);
+        assert_eq!(reqs[0].method, "POST");
+        assert_eq!(reqs[0].path, "/users/me/threads/t1/modify");
+        assert_eq!(
+            body(&reqs[0]),
+            serde_json::json!({
+                "addLabelIds": ["Label_2"],
+                "removeLabelIds": ["UNREAD"],
+            })
+        );
+        assert_eq!(reqs[1].path, "/users/me/threads/odd/modify");
+        assert_eq!(
+            body(&reqs[1]),
+            serde_json::json!({
+                "addLabelIds": [],
+                "removeLabelIds": ["INBOX"],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_batch_delete() {
+        for (count, sizes) in [(1000, vec![1000]), (1001, vec![1000, 1])] {
+            let srv = modify_stub().await;
+            let gm = client(&srv);
+
+            let ids = (0..count).map(|n| format!("m{}", n)).collect::<Vec<_>>();
+            let r = gm.messages_batch_delete(&ids).await.unwrap();
+
+            /*
+             * Outside of a dry run, every message we asked to delete is
+             * reported as deleted.
+             */
+            assert!(!r.dry_run);
+            assert_eq!(r.deleted, ids);
+            assert!(r.missing.is_empty());
+            assert!(r.rate_limited.is_empty());
+
+            let reqs = srv.requests();
+            assert_eq!(
+                reqs.iter()
+                    .map(|r| body(r)["ids"].as_array().unwrap().len())
+                    .collect::<Vec<_>>(),
+                sizes
+            );
+            let mut sent = Vec::new();
+            for r in &reqs {
+                assert_eq!(r.method, "POST");
+                assert_eq!(r.path, "/users/me/messages/batchDelete");
+                let b = body(r);
+                assert_eq!(b.as_object().unwrap().len(), 1);
+                sent.extend(
+                    b["ids"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|id| id.as_str().unwrap().to_string()),
+                );
+            }
+            assert_eq!(sent, ids);
         }
     }
+
+    #[tokio::test]
+    async fn message_trash_delete() {
+        let srv = modify_stub().await;
+        let gm = client(&srv);
+
+        gm.message_trash("m1").await.unwrap();
+        gm.message_untrash("m1").await.unwrap();
+        gm.message_delete("m1").await.unwrap();
+
+        let reqs = srv.requests();
+        assert_eq!(
+            reqs.iter()
+                .map(|r| (r.method.as_str(), r.path.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("POST", "/users/me/messages/m1/trash"),
+                ("POST", "/users/me/messages/m1/untrash"),
+                ("DELETE", "/users/me/messages/m1"),
+            ]
+        );
+    }
+
+    /**
+     * A stub that answers requests for the attachment "good" with the
+     * contents "hello world", and for "short" with the same contents but a
+     * larger size.  Responses are delayed so that concurrent requests
+     * overlap.
+     */
+    async fn attachment_stub() -> Server {
+        Server::start(|req| {
+            let size = match req.path.rsplit('/').next().unwrap() {
+                "good" => 11,
+                "short" => 20,
+                _ => return Response::new(404),
+            };
+            Response::json(
+                200,
+                &serde_json::json!({
+                    "size": size,
+                    "data": "aGVsbG8gd29ybGQ",
+                }),
+            )
+            .delay(Duration::from_millis(50))
+        })
+        .await
+    }
+
+    fn attachment_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rgmail-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn attachment_get() {
+        let srv = attachment_stub().await;
+        let gm = client(&srv);
+
+        assert_eq!(
+            gm.attachment_get("m", "good").await.unwrap(),
+            b"hello world"
+        );
+        assert!(matches!(
+            gm.attachment_get("m", "short").await,
+            Err(Error::Decode(_))
+        ));
+
+        let reqs = srv.requests();
+        assert_eq!(reqs[0].method, "GET");
+        assert_eq!(reqs[0].path, "/users/me/messages/m/attachments/good");
+    }
+
+    #[tokio::test]
+    async fn attachment_save() {
+        let srv = attachment_stub().await;
+        let gm = client(&srv);
+        let dir = attachment_dir("attachment-save");
+        let path = dir.join("hello.txt");
+
+        /*
+         * Saves to the same file that overlap must each write their own
+         * temporary file, and leave a complete file behind.
+         */
+        let saves = (0..8)
+            .map(|_| gm.attachment_save("m", "good", &path, None))
+            .collect::<Vec<_>>();
+        for size in futures_util::future::join_all(saves).await {
+            assert_eq!(size.unwrap(), 11);
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        assert_eq!(dir_entries(&dir), ["hello.txt"]);
+        std::fs::remove_file(&path).unwrap();
+
+        /*
+         * If the attachment is too large, or is not the size the server said
+         * it would be, nothing is left behind.
+         */
+        assert!(matches!(
+            gm.attachment_save("m", "good", &path, Some(5)).await,
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(
+            gm.attachment_save("m", "short", &path, None).await,
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(
+            gm.attachment_save("m", "gone", &path, None).await,
+            Err(Error::NotFound { .. })
+        ));
+        assert!(dir_entries(&dir).is_empty());
+
+        std::fs::remove_dir(&dir).unwrap();
+    }
 }
@@ -8,17 +8,15 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
 
-use anyhow::Result;
 use futures_core::stream::Stream;
-use reqwest::header;
 use serde::Deserialize;
 use serde_aux::prelude::*;
 use slog::debug;
 
+use super::error::{json, Result};
 use super::gmail;
 use super::messages;
 use super::types::*;
-use super::util::*;
 
 pub struct HistoryConfig {
     parent: Arc<gmail::GMailInner>,
@@ -82,7 +80,7 @@ pub struct History {
     page_token: Option<String>,
     infl: VecDeque<RHistoryRecord>,
     final_id: Option<u64>,
-    fetch: Option<Pin<Box<(dyn Future<Output = Result<RHistory>>)>>>,
+    fetch: Option<Pin<Box<dyn Future<Output = Result<RHistory>>>>>,
 }
 
 impl Stream for History {
@@ -181,33 +179,32 @@ async fn fetch_page(
 ) -> Result<RHistory> {
     let log = &c.parent.log;
 
-    let url = bu("users/me/history");
+    debug!(log, "requesting more history (pt {:?})", page_token);
 
-    c.parent.auth.check_refresh().await?;
+    let url = c.parent.url("users/me/history");
 
-    let mut req = c.parent.client.get(&url).header(
-        header::AUTHORIZATION,
-        format!("Bearer {}", c.parent.auth.access_token()),
-    );
+    let res = c
+        .parent
+        .execute(|| {
+            let mut req = c.parent.client.get(&url);
 
-    req = req.query(&[("startHistoryId", c.start_at.to_string())]);
-    if let Some(label_id) = &c.label_id {
-        req = req.query(&[("labelId", label_id)]);
-    }
-    for t in &c.history_types {
-        req = req.query(&[("historyTypes", t)]);
-    }
-    if let Some(pt) = &page_token {
-        req = req.query(&[("pageToken", pt)]);
-    }
-    if let Some(pp) = &c.perpage {
-        req = req.query(&[("maxResults", pp.to_string())]);
-    }
-
-    let req = req.build()?;
-    debug!(log, "request for page: {}", req.url());
+            req = req.query(&[("startHistoryId", c.start_at.to_string())]);
+            if let Some(label_id) = &c.label_id {
+                req = req.query(&[("labelId", label_id)]);
+            }
+            for t in &c.history_types {
+                req = req.query(&[("historyTypes", t)]);
+            }
+            if let Some(pt) = &page_token {
+                req = req.query(&[("pageToken", pt)]);
+            }
+            if let Some(pp) = &c.perpage {
+                req = req.query(&[("maxResults", pp.to_string())]);
+            }
 
-    let res = c.parent.client.execute(req).await?.error_for_status()?;
+            req
+        })
+        .await?;
 
-    Ok(res.json().await?)
+    json(res).await
 }
@@ -4,10 +4,24 @@
 
 #![allow(unused_imports)] /* XXX */
 
+mod attachment;
+pub mod batch;
+mod drafts;
+mod error;
 pub mod gauth;
 pub mod gmail;
 mod history;
 mod messages;
 mod multipart;
+mod paging;
+pub mod retry;
+pub mod scope;
+mod threads;
+pub mod token;
 mod types;
 mod util;
+
+#[cfg(test)]
+mod testutil;
+
+pub use error::{Error, GoogleError, GoogleErrorDetail, Result};
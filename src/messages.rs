@@ -2,20 +2,14 @@
  * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
  */
 
-use anyhow::Result;
-use futures_core::Stream;
-use reqwest::header;
 use serde::Deserialize;
 use slog::debug;
-use std::collections::VecDeque;
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-use std::task::Poll;
+use std::sync::Arc;
 
+use super::error::{json, Result};
 use super::gmail;
+use super::paging::{Page, Pages};
 use super::types::*;
-use super::util::*;
 
 pub struct MessagesConfig {
     parent: Arc<gmail::GMailInner>,
@@ -74,157 +68,53 @@ impl MessagesConfig {
     }
 
     pub fn start(self) -> Messages {
-        Messages {
-            fin: false,
-            previous_token: None,
-            page_token: self.resume_from_token.clone(),
-            c: Arc::new(self),
-            infl: VecDeque::new(),
-            fetch: None,
-        }
-    }
-}
-
-pub struct Messages {
-    c: Arc<MessagesConfig>,
-    fin: bool,
-    previous_token: Option<String>,
-    page_token: Option<String>,
-    infl: VecDeque<RMessage>,
-    fetch: Option<Pin<Box<(dyn Future<Output = Result<RMessages>>)>>>,
-}
+        let log = self.parent.log.clone();
+        let page_token = self.resume_from_token.clone();
+        let c = Arc::new(self);
 
-impl Messages {
-    pub fn resume_token(&self) -> Option<String> {
-        self.previous_token.clone()
+        Pages::new(log, "messages", page_token, move |pt| {
+            fetch_page(Arc::clone(&c), pt)
+        })
     }
 }
 
-impl Stream for Messages {
-    type Item = Result<RMessage>;
-
-    fn poll_next(
-        mut self: Pin<&mut Messages>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        loop {
-            /*
-             * If there is something in the queue already, we can just
-             * return it.
-             */
-            if let Some(rm) = self.infl.pop_front() {
-                return Poll::Ready(Some(Ok(rm)));
-            }
-
-            /*
-             * If we have already read the last page, there is nothing left
-             * to do.
-             */
-            if self.fin {
-                debug!(self.c.parent.log, "finished completely!");
-                return Poll::Ready(None);
-            }
-
-            /*
-             * At this point, we either need to spawn a task to load the next
-             * page from the server, or if we already have a running task, we
-             * need to wait for it to be finished.
-             */
-            if let Some(fetch) = self.fetch.as_mut() {
-                let pin = Pin::new(fetch);
-                match pin.poll(cx) {
-                    Poll::Pending => return Poll::Pending,
-                    Poll::Ready(Ok(o)) => {
-                        self.fetch = None;
-
-                        debug!(
-                            self.c.parent.log,
-                            "result count estimate: {}", o.result_size_estimate
-                        );
-                        debug!(
-                            self.c.parent.log,
-                            "new next page token: {:?}", o.next_page_token
-                        );
-
-                        self.previous_token = self.page_token.clone();
-                        self.page_token = o.next_page_token;
-                        if self.page_token.is_none() {
-                            /*
-                             * If we do not have a next page token, the stream
-                             * is finished.
-                             */
-                            self.fin = true;
-                        }
-
-                        debug!(
-                            self.c.parent.log,
-                            "got {} messages records",
-                            o.messages.len()
-                        );
-
-                        for rm in o.messages {
-                            self.infl.push_back(rm);
-                        }
-                    }
-                    Poll::Ready(Err(e)) => {
-                        self.fetch = None;
-                        return Poll::Ready(Some(Err(e)));
-                    }
-                }
-            } else {
-                let pt = self.page_token.clone();
-
-                /*
-                 * No fetch was in progress.  Start one.
-                 */
-                debug!(
-                    self.c.parent.log,
-                    "requesting more messages (pt {:?})", pt,
-                );
-                self.fetch =
-                    Some(Box::pin(fetch_page(Arc::clone(&self.c), pt)));
-            }
-        }
-    }
-}
+pub type Messages = Pages<RMessage>;
 
 async fn fetch_page(
     c: Arc<MessagesConfig>,
     page_token: Option<String>,
-) -> Result<RMessages> {
+) -> Result<Page<RMessage>> {
     let log = &c.parent.log;
 
-    debug!(log, "requesting more message IDs (pt {:?})", page_token);
-
-    let url = bu("users/me/messages");
+    let url = c.parent.url("users/me/messages");
 
-    c.parent.auth.check_refresh().await?;
+    let res = c
+        .parent
+        .execute(|| {
+            let mut req = c.parent.client.get(&url);
 
-    let mut req = c.parent.client.get(&url).header(
-        header::AUTHORIZATION,
-        format!("Bearer {}", c.parent.auth.access_token()),
-    );
-
-    if let Some(q) = &c.q {
-        req = req.query(&[("q", q)]);
-    }
-    if c.spamtrash {
-        req = req.query(&[("includeSpamTrash", "true")]);
-    }
-    for l in &c.label_ids {
-        req = req.query(&[("labelIds", l)]);
-    }
-    if let Some(pt) = &page_token {
-        req = req.query(&[("pageToken", pt)]);
-    }
-    if let Some(pp) = &c.perpage {
-        req = req.query(&[("maxResults", pp.to_string())]);
-    }
+            if let Some(q) = &c.q {
+                req = req.query(&[("q", q)]);
+            }
+            if c.spamtrash {
+                req = req.query(&[("includeSpamTrash", "true")]);
+            }
+            for l in &c.label_ids {
+                req = req.query(&[("labelIds", l)]);
+            }
+            if let Some(pt) = &page_token {
+                req = req.query(&[("pageToken", pt)]);
+            }
+            if let Some(pp) = &c.perpage {
+                req = req.query(&[("maxResults", pp.to_string())]);
+            }
 
-    let req = req.build()?;
-    debug!(log, "request for page: {}", req.url());
+            req
+        })
+        .await?;
 
-    let res = c.parent.client.execute(req).await?.error_for_status()?;
+    let o: RMessages = json(res).await?;
+    debug!(log, "result count estimate: {}", o.result_size_estimate);
 
-    Ok(res.json().await?)
+    Ok((o.messages, o.next_page_token))
 }
@@ -0,0 +1,210 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use futures_core::Stream;
+use slog::{debug, Logger};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+
+use super::error::Result;
+
+/**
+ * One page of a list call: the items on the page, and the token for the next
+ * page if there is one.
+ */
+pub(crate) type Page<T> = (Vec<T>, Option<String>);
+
+type FetchPage<T> = Pin<Box<dyn Future<Output = Result<Page<T>>>>>;
+
+/**
+ * A stream of the items from a list call that returns results in pages, where
+ * each page includes a token that is used to request the next page.
+ */
+pub struct Pages<T> {
+    log: Logger,
+    what: &'static str,
+    fetch_page: Box<dyn Fn(Option<String>) -> FetchPage<T>>,
+    fin: bool,
+    previous_token: Option<String>,
+    page_token: Option<String>,
+    infl: VecDeque<T>,
+    fetch: Option<FetchPage<T>>,
+}
+
+impl<T> Pages<T> {
+    /**
+     * Create a stream that will call fetch_page with the token for each page
+     * in turn, starting with the specified token.  If there is no token, the
+     * stream starts at the first page.
+     */
+    pub(crate) fn new<F, Fut>(
+        log: Logger,
+        what: &'static str,
+        page_token: Option<String>,
+        fetch_page: F,
+    ) -> Pages<T>
+    where
+        F: Fn(Option<String>) -> Fut + 'static,
+        Fut: Future<Output = Result<Page<T>>> + 'static,
+    {
+        Pages {
+            log,
+            what,
+            fetch_page: Box::new(move |pt| Box::pin(fetch_page(pt))),
+            fin: false,
+            previous_token: None,
+            page_token,
+            infl: VecDeque::new(),
+            fetch: None,
+        }
+    }
+
+    /**
+     * Return the token for the page that produced the most recent items, which
+     * can be passed to resume_from_token() to pick up from there later.
+     */
+    pub fn resume_token(&self) -> Option<String> {
+        self.previous_token.clone()
+    }
+}
+
+impl<T: Unpin> Stream for Pages<T> {
+    type Item = Result<T>;
+
+    fn poll_next(
+        mut self: Pin<&mut Pages<T>>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            /*
+             * If there is something in the queue already, we can just
+             * return it.
+             */
+            if let Some(item) = self.infl.pop_front() {
+                return Poll::Ready(Some(Ok(item)));
+            }
+
+            /*
+             * If we have already read the last page, there is nothing left
+             * to do.
+             */
+            if self.fin {
+                debug!(self.log, "finished completely!");
+                return Poll::Ready(None);
+            }
+
+            /*
+             * At this point, we either need to spawn a task to load the next
+             * page from the server, or if we already have a running task, we
+             * need to wait for it to be finished.
+             */
+            if let Some(fetch) = self.fetch.as_mut() {
+                match fetch.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok((items, next_page_token))) => {
+                        self.fetch = None;
+
+                        debug!(
+                            self.log,
+                            "new next page token: {:?}", next_page_token
+                        );
+
+                        self.previous_token = self.page_token.take();
+                        self.page_token = next_page_token;
+                        if self.page_token.is_none() {
+                            /*
+                             * If we do not have a next page token, the stream
+                             * is finished.
+                             */
+                            self.fin = true;
+                        }
+
+                        debug!(
+                            self.log,
+                            "got {} {} records",
+                            items.len(),
+                            self.what
+                        );
+
+                        self.infl.extend(items);
+                    }
+                    Poll::Ready(Err(e)) => {
+                        self.fetch = None;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+            } else {
+                let pt = self.page_token.clone();
+
+                /*
+                 * No fetch was in progress.  Start one.
+                 */
+                debug!(self.log, "requesting more {} (pt {:?})", self.what, pt);
+                self.fetch = Some((self.fetch_page)(pt));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::logger;
+    use crate::Error;
+    use futures_util::StreamExt;
+
+    /**
+     * A stream over three pages of two items each, where each page token is
+     * the number of the page.
+     */
+    fn pages(start: Option<String>) -> Pages<u32> {
+        Pages::new(logger(), "numbers", start, |pt| async move {
+            let n: u32 = pt.map(|pt| pt.parse().unwrap()).unwrap_or(0);
+            if n > 2 {
+                return Err(Error::Decode(format!("bad page {}", n)));
+            }
+            let next = if n < 2 {
+                Some((n + 1).to_string())
+            } else {
+                None
+            };
+            Ok((vec![n * 2, n * 2 + 1], next))
+        })
+    }
+
+    #[tokio::test]
+    async fn resume() {
+        let mut s = pages(None);
+        let mut seen = Vec::new();
+        while let Some(i) = s.next().await {
+            seen.push((i.unwrap(), s.resume_token()));
+        }
+        assert_eq!(
+            seen,
+            vec![
+                (0, None),
+                (1, None),
+                (2, Some("1".to_string())),
+                (3, Some("1".to_string())),
+                (4, Some("2".to_string())),
+                (5, Some("2".to_string())),
+            ]
+        );
+
+        /*
+         * Resuming from a token must produce that page again.
+         */
+        let s = pages(Some("2".to_string()));
+        let rest = s.map(|i| i.unwrap()).collect::<Vec<_>>().await;
+        assert_eq!(rest, vec![4, 5]);
+    }
+
+    #[tokio::test]
+    async fn fetch_error() {
+        let mut s = pages(Some("3".to_string()));
+        assert!(matches!(s.next().await, Some(Err(Error::Decode(_)))));
+    }
+}
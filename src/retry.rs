@@ -0,0 +1,294 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::error::Error;
+use super::gauth::AuthError;
+
+/**
+ * How to retry requests that fail for reasons that may be transient, such as
+ * rate limiting or server errors.  Between attempts we wait for an
+ * exponentially increasing, jittered, interval; if the server provides a
+ * Retry-After header, we wait at least that long.
+ */
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    max_elapsed: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 8,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(32),
+            multiplier: 2.0,
+            max_elapsed: Some(Duration::from_secs(120)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        Default::default()
+    }
+
+    /**
+     * A policy that never retries.
+     */
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new().max_retries(0)
+    }
+
+    pub fn max_retries(mut self, n: u32) -> RetryPolicy {
+        self.max_retries = n;
+        self
+    }
+
+    pub fn initial_backoff(mut self, d: Duration) -> RetryPolicy {
+        self.initial_backoff = d;
+        self
+    }
+
+    pub fn max_backoff(mut self, d: Duration) -> RetryPolicy {
+        self.max_backoff = d;
+        self
+    }
+
+    pub fn multiplier(mut self, m: f64) -> RetryPolicy {
+        self.multiplier = m.max(1.0);
+        self
+    }
+
+    /**
+     * Give up once this much time has passed since the first attempt, even if
+     * we have retries remaining.  With None, only the retry count applies.
+     */
+    pub fn max_elapsed(mut self, d: Option<Duration>) -> RetryPolicy {
+        self.max_elapsed = d;
+        self
+    }
+
+    /**
+     * The jittered backoff interval before the retry that follows the
+     * specified (zero-based) attempt.
+     */
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        /*
+         * The exponential interval soon exceeds anything a Duration can
+         * hold, so clamp it while it is still a floating point number of
+         * seconds.  It may even be infinite, but never NaN, as the initial
+         * interval is finite and the multiplier is at least one.
+         */
+        let initial = self.initial_backoff.as_secs_f64();
+        let base = if initial > 0.0 {
+            let exp = self.multiplier.powi(attempt.min(1024) as i32);
+            (initial * exp).min(self.max_backoff.as_secs_f64())
+        } else {
+            0.0
+        };
+
+        /*
+         * Wait for somewhere between half and all of the full interval, so
+         * that concurrent clients do not retry in lockstep.  Converting the
+         * largest possible maximum back into a Duration may round up past
+         * what a Duration can hold.
+         */
+        let secs = rand::thread_rng().gen_range(base / 2.0..=base);
+        Duration::try_from_secs_f64(secs).unwrap_or(self.max_backoff)
+    }
+
+    /**
+     * Decide whether to retry after a failed attempt and, if so, how long to
+     * wait first.  The elapsed time is measured from the first attempt.  A
+     * request that is not idempotent is retried only if it cannot have taken
+     * effect; see is_retryable().
+     */
+    pub(crate) fn delay(
+        &self,
+        e: &Error,
+        idempotent: bool,
+        attempt: u32,
+        elapsed: Duration,
+    ) -> Option<Duration> {
+        if !is_retryable(e, idempotent) {
+            return None;
+        }
+
+        let retry_after = match e {
+            Error::RateLimited { retry_after, .. } => *retry_after,
+            Error::Auth(AuthError::RateLimited { retry_after }) => *retry_after,
+            _ => None,
+        };
+
+        self.next_delay(attempt, elapsed, retry_after)
+    }
+
+    /**
+     * How long to wait before making another attempt, given that the
+     * specified (zero-based) attempt has failed in a way that is worth
+     * retrying.  If the server asked us to wait for some minimum period, we
+     * will wait at least that long.
+     */
+    pub(crate) fn next_delay(
+        &self,
+        attempt: u32,
+        elapsed: Duration,
+        at_least: Option<Duration>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+
+        let mut delay = self.backoff(attempt);
+        if let Some(ra) = at_least {
+            delay = delay.max(ra);
+        }
+
+        if let Some(max) = self.max_elapsed {
+            match elapsed.checked_add(delay) {
+                Some(t) if t <= max => (),
+                _ => return None,
+            }
+        }
+
+        Some(delay)
+    }
+}
+
+/**
+ * Could the same request succeed if it were made again later?  Requests that
+ * are not idempotent, such as sending a message, must not be repeated if the
+ * first attempt may have taken effect.  A server error or a timeout leaves us
+ * unable to tell, so in that case we only retry when the server rejected the
+ * request for rate limiting, or when we never got as far as sending it.
+ */
+pub(crate) fn is_retryable(e: &Error, idempotent: bool) -> bool {
+    match e {
+        Error::RateLimited { .. } => true,
+        Error::Auth(ae) => ae.is_transient(),
+        Error::Request(re) if re.is_connect() => true,
+        _ if !idempotent => false,
+        Error::Http { status, .. } => {
+            matches!(status, 500 | 502 | 503 | 504)
+        }
+        Error::Request(re) => re.is_timeout(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_jitter() {
+        let rp = RetryPolicy::new();
+        for _ in 0..100 {
+            let d = rp.backoff(0);
+            assert!(d >= Duration::from_millis(250) && d <= rp.initial_backoff);
+            let d = rp.backoff(20);
+            assert!(d >= Duration::from_secs(16) && d <= rp.max_backoff);
+        }
+    }
+
+    #[test]
+    fn backoff_overflow() {
+        let policies = [
+            RetryPolicy::new()
+                .initial_backoff(Duration::from_secs(1))
+                .max_retries(100)
+                .max_elapsed(None),
+            RetryPolicy::new().multiplier(10.0).max_elapsed(None),
+            RetryPolicy::new()
+                .multiplier(f64::MAX)
+                .max_backoff(Duration::MAX)
+                .max_elapsed(Some(Duration::from_secs(3600))),
+        ];
+
+        for rp in policies {
+            for attempt in [0, 19, 20, 21, 63, 64, 65, 99, 1000, u32::MAX] {
+                assert!(rp.backoff(attempt) <= rp.max_backoff);
+                rp.next_delay(attempt, Duration::from_secs(1), None);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_zero() {
+        let rp = RetryPolicy::new()
+            .initial_backoff(Duration::ZERO)
+            .multiplier(f64::INFINITY);
+        assert_eq!(rp.backoff(100), Duration::ZERO);
+        assert_eq!(
+            rp.next_delay(0, Duration::ZERO, None),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retryable() {
+        let rate = Error::from_status(
+            429,
+            None,
+            br#"{"error": {"code": 429, "message": "slow down"}}"#,
+        );
+        let unavailable = Error::from_status(503, None, b"");
+        let auth = Error::Auth(AuthError::Transient {
+            status: 500,
+            message: String::new(),
+        });
+        let bad = Error::from_status(400, None, b"");
+
+        for idempotent in [true, false] {
+            assert!(is_retryable(&rate, idempotent));
+            assert!(is_retryable(&auth, idempotent));
+            assert!(!is_retryable(&bad, idempotent));
+        }
+        assert!(is_retryable(&unavailable, true));
+        assert!(!is_retryable(&unavailable, false));
+    }
+
+    #[test]
+    fn delay_limits() {
+        let rp = RetryPolicy::new().max_retries(2);
+        assert!(rp.next_delay(1, Duration::ZERO, None).is_some());
+        assert!(rp.next_delay(2, Duration::ZERO, None).is_none());
+
+        let ra = Duration::from_secs(10);
+        assert!(rp.next_delay(0, Duration::ZERO, Some(ra)).unwrap() >= ra);
+        assert!(rp
+            .next_delay(0, Duration::from_secs(115), Some(ra))
+            .is_none());
+        assert!(rp.next_delay(0, Duration::MAX, None).is_none());
+    }
+
+    #[test]
+    fn delay_retry_after() {
+        let rp = RetryPolicy::new();
+        let ra = Duration::from_secs(10);
+
+        /*
+         * The token endpoint may ask us to wait, just as the API may.
+         */
+        for e in [
+            Error::from_status(429, Some(ra), b""),
+            Error::Auth(AuthError::RateLimited {
+                retry_after: Some(ra),
+            }),
+        ] {
+            for idempotent in [true, false] {
+                let d = rp.delay(&e, idempotent, 0, Duration::ZERO).unwrap();
+                assert!(d >= ra, "{:?} after {:?}", d, e);
+            }
+        }
+    }
+}
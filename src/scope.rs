@@ -0,0 +1,234 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/**
+ * An OAuth scope that may be requested from, and granted by, the user.  Scopes
+ * that do not have their own variant can be expressed with Other.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Scope {
+    Profile,
+    Email,
+    /**
+     * Full access to the mailbox, including permanent deletion.
+     */
+    Mail,
+    GmailReadonly,
+    GmailModify,
+    GmailCompose,
+    GmailSend,
+    GmailInsert,
+    GmailLabels,
+    GmailMetadata,
+    GmailSettingsBasic,
+    GmailSettingsSharing,
+    Other(String),
+}
+
+const GMAIL: &str = "https://www.googleapis.com/auth/gmail.";
+const USERINFO: &str = "https://www.googleapis.com/auth/userinfo.";
+
+impl Scope {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Scope::Profile => "profile",
+            Scope::Email => "email",
+            Scope::Mail => "https://mail.google.com/",
+            Scope::GmailReadonly => {
+                "https://www.googleapis.com/auth/gmail.readonly"
+            }
+            Scope::GmailModify => {
+                "https://www.googleapis.com/auth/gmail.modify"
+            }
+            Scope::GmailCompose => {
+                "https://www.googleapis.com/auth/gmail.compose"
+            }
+            Scope::GmailSend => "https://www.googleapis.com/auth/gmail.send",
+            Scope::GmailInsert => {
+                "https://www.googleapis.com/auth/gmail.insert"
+            }
+            Scope::GmailLabels => {
+                "https://www.googleapis.com/auth/gmail.labels"
+            }
+            Scope::GmailMetadata => {
+                "https://www.googleapis.com/auth/gmail.metadata"
+            }
+            Scope::GmailSettingsBasic => {
+                "https://www.googleapis.com/auth/gmail.settings.basic"
+            }
+            Scope::GmailSettingsSharing => {
+                "https://www.googleapis.com/auth/gmail.settings.sharing"
+            }
+            Scope::Other(s) => s.as_str(),
+        }
+    }
+
+    /**
+     * Does a grant of this scope also allow everything that the other scope
+     * allows?
+     */
+    pub fn implies(&self, other: &Scope) -> bool {
+        if self == other {
+            return true;
+        }
+
+        match self {
+            Scope::Mail => matches!(
+                other,
+                Scope::GmailReadonly
+                    | Scope::GmailModify
+                    | Scope::GmailCompose
+                    | Scope::GmailSend
+                    | Scope::GmailInsert
+                    | Scope::GmailLabels
+                    | Scope::GmailMetadata
+            ),
+            Scope::GmailModify => matches!(
+                other,
+                Scope::GmailReadonly
+                    | Scope::GmailCompose
+                    | Scope::GmailSend
+                    | Scope::GmailInsert
+                    | Scope::GmailLabels
+                    | Scope::GmailMetadata
+            ),
+            Scope::GmailReadonly => matches!(other, Scope::GmailMetadata),
+            Scope::GmailCompose => matches!(other, Scope::GmailSend),
+            _ => false,
+        }
+    }
+
+    /**
+     * Parse a space-separated list of scopes, as found in token responses.
+     */
+    pub fn parse_list(s: &str) -> BTreeSet<Scope> {
+        s.split_whitespace().map(Scope::from).collect()
+    }
+}
+
+impl From<&str> for Scope {
+    fn from(s: &str) -> Scope {
+        /*
+         * The server reports the short "profile" and "email" scopes using
+         * their full URL form.
+         */
+        match s {
+            "profile" => return Scope::Profile,
+            "email" => return Scope::Email,
+            "https://mail.google.com/" => return Scope::Mail,
+            _ => (),
+        }
+
+        if let Some(u) = s.strip_prefix(USERINFO) {
+            match u {
+                "profile" => return Scope::Profile,
+                "email" => return Scope::Email,
+                _ => (),
+            }
+        }
+
+        if let Some(g) = s.strip_prefix(GMAIL) {
+            match g {
+                "readonly" => return Scope::GmailReadonly,
+                "modify" => return Scope::GmailModify,
+                "compose" => return Scope::GmailCompose,
+                "send" => return Scope::GmailSend,
+                "insert" => return Scope::GmailInsert,
+                "labels" => return Scope::GmailLabels,
+                "metadata" => return Scope::GmailMetadata,
+                "settings.basic" => return Scope::GmailSettingsBasic,
+                "settings.sharing" => return Scope::GmailSettingsSharing,
+                _ => (),
+            }
+        }
+
+        Scope::Other(s.to_string())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Scope, Self::Err> {
+        Ok(Scope::from(s))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_list() {
+        let scopes = Scope::parse_list(
+            "https://www.googleapis.com/auth/userinfo.email  openid \
+            https://mail.google.com/ profile \
+            https://www.googleapis.com/auth/gmail.settings.basic",
+        );
+        assert_eq!(
+            scopes.into_iter().collect::<Vec<_>>(),
+            [
+                Scope::Profile,
+                Scope::Email,
+                Scope::Mail,
+                Scope::GmailSettingsBasic,
+                Scope::Other("openid".into()),
+            ]
+        );
+
+        assert!(Scope::parse_list("").is_empty());
+        assert!(Scope::parse_list(" \t").is_empty());
+
+        /*
+         * Each scope we know about must survive a round trip.
+         */
+        for s in [
+            Scope::Profile,
+            Scope::Email,
+            Scope::Mail,
+            Scope::GmailReadonly,
+            Scope::GmailModify,
+            Scope::GmailCompose,
+            Scope::GmailSend,
+            Scope::GmailInsert,
+            Scope::GmailLabels,
+            Scope::GmailMetadata,
+            Scope::GmailSettingsBasic,
+            Scope::GmailSettingsSharing,
+        ] {
+            assert_eq!(Scope::from(s.as_str()), s);
+        }
+    }
+
+    #[test]
+    fn implies() {
+        let other = Scope::Other("openid".into());
+        assert!(other.implies(&other));
+        assert!(!Scope::Mail.implies(&other));
+
+        assert!(Scope::Mail.implies(&Scope::GmailModify));
+        assert!(Scope::Mail.implies(&Scope::GmailMetadata));
+        assert!(Scope::GmailModify.implies(&Scope::GmailReadonly));
+        assert!(Scope::GmailModify.implies(&Scope::GmailSend));
+        assert!(Scope::GmailReadonly.implies(&Scope::GmailMetadata));
+        assert!(Scope::GmailCompose.implies(&Scope::GmailSend));
+
+        assert!(!Scope::GmailModify.implies(&Scope::Mail));
+        assert!(!Scope::GmailReadonly.implies(&Scope::GmailModify));
+        assert!(!Scope::GmailSend.implies(&Scope::GmailCompose));
+        assert!(!Scope::GmailMetadata.implies(&Scope::GmailReadonly));
+        assert!(!Scope::Mail.implies(&Scope::GmailSettingsBasic));
+        assert!(!Scope::Mail.implies(&Scope::Profile));
+    }
+}
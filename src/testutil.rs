@@ -0,0 +1,219 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+/*
+ * A minimal HTTP server on the loopback interface, so that tests can stand in
+ * for the Google endpoints without any network access.  Each connection gets
+ * one request and one response.
+ */
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /**
+     * Decode a form-encoded request body.
+     */
+    pub fn form(&self) -> HashMap<String, String> {
+        reqwest::Url::parse("http://form/")
+            .unwrap()
+            .join(&format!("?{}", String::from_utf8_lossy(&self.body)))
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+}
+
+pub(crate) struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    delay: Option<Duration>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            delay: None,
+        }
+    }
+
+    pub fn json(status: u16, body: &serde_json::Value) -> Response {
+        Response::new(status)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /**
+     * Wait for this long before sending the response, as a slow server
+     * would.
+     */
+    pub fn delay(mut self, delay: Duration) -> Response {
+        self.delay = Some(delay);
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+pub(crate) struct Server {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    /**
+     * Start a server that answers each request with the result of the
+     * handler.  The server runs until the test runtime shuts down.
+     */
+    pub async fn start<F>(handler: F) -> Server
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}",
+            listener.local_addr().unwrap().port()
+        );
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let reqs = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let (sock, _) = listener.accept().await.unwrap();
+                let reqs = Arc::clone(&reqs);
+                let handler = Arc::clone(&handler);
+                tokio::spawn(async move {
+                    serve(sock, reqs, handler).await;
+                });
+            }
+        });
+
+        Server { url, requests }
+    }
+
+    /**
+     * The base URL of the server, without a trailing slash.
+     */
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /**
+     * The requests received so far, in the order they arrived.
+     */
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn serve(
+    mut sock: TcpStream,
+    reqs: Arc<Mutex<Vec<Request>>>,
+    handler: Arc<Handler>,
+) {
+    let req = if let Some(req) = read_request(&mut sock).await {
+        req
+    } else {
+        return;
+    };
+    reqs.lock().unwrap().push(req.clone());
+
+    let res = handler(&req);
+    if let Some(delay) = res.delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let mut out = format!("HTTP/1.1 {} Stub\r\n", res.status);
+    for (name, value) in &res.headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        res.body.len()
+    ));
+
+    let mut out = out.into_bytes();
+    out.extend_from_slice(&res.body);
+    sock.write_all(&out).await.ok();
+    sock.shutdown().await.ok();
+}
+
+async fn read_request(sock: &mut TcpStream) -> Option<Request> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    loop {
+        let mut chunk = [0u8; 4096];
+        let sz = sock.read(&mut chunk).await.ok()?;
+        if sz == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..sz]);
+
+        let mut hdrs = [httparse::EMPTY_HEADER; 64];
+        let mut parser = httparse::Request::new(&mut hdrs);
+        let c = match parser.parse(&buf).ok()? {
+            httparse::Status::Complete(c) => c,
+            httparse::Status::Partial => continue,
+        };
+
+        let headers: HashMap<String, String> = parser
+            .headers
+            .iter()
+            .map(|h| {
+                (
+                    h.name.to_ascii_lowercase(),
+                    String::from_utf8_lossy(h.value).to_string(),
+                )
+            })
+            .collect();
+        let len: usize = headers
+            .get("content-length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        if buf.len() < c + len {
+            continue;
+        }
+
+        return Some(Request {
+            method: parser.method?.to_string(),
+            path: parser.path?.to_string(),
+            headers,
+            body: buf[c..c + len].to_vec(),
+        });
+    }
+}
+
+pub(crate) fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, slog::o!())
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use serde::Deserialize;
+use slog::debug;
+use std::sync::Arc;
+
+use super::error::{json, Result};
+use super::gmail::{self, Thread};
+use super::paging::{Page, Pages};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RThreads {
+    #[serde(default)]
+    threads: Vec<Thread>,
+    next_page_token: Option<String>,
+    #[serde(default)]
+    result_size_estimate: u64,
+}
+
+pub struct ThreadsConfig {
+    parent: Arc<gmail::GMailInner>,
+    perpage: Option<u32>,
+    q: Option<String>,
+    spamtrash: bool,
+    label_ids: Vec<String>,
+    resume_from_token: Option<String>,
+}
+
+impl ThreadsConfig {
+    pub(crate) fn new(parent: &gmail::GMail) -> ThreadsConfig {
+        ThreadsConfig {
+            parent: Arc::clone(&parent.0),
+            perpage: None,
+            q: None,
+            spamtrash: false,
+            label_ids: Vec::new(),
+            resume_from_token: None,
+        }
+    }
+
+    pub fn query<S: AsRef<str>>(mut self, s: S) -> ThreadsConfig {
+        self.q = Some(s.as_ref().to_string());
+        self
+    }
+
+    pub fn include_spam_trash(mut self, i: bool) -> ThreadsConfig {
+        self.spamtrash = i;
+        self
+    }
+
+    pub fn resume_from_token(mut self, s: &str) -> ThreadsConfig {
+        self.resume_from_token = Some(s.to_string());
+        self
+    }
+
+    pub fn batch_size(mut self, n: u32) -> ThreadsConfig {
+        self.perpage = Some(n);
+        self
+    }
+
+    pub fn labels_clear(mut self) -> ThreadsConfig {
+        self.label_ids.clear();
+        self
+    }
+
+    pub fn label_add(mut self, label_id: &str) -> ThreadsConfig {
+        let s = label_id.to_string();
+
+        if !self.label_ids.contains(&s) {
+            self.label_ids.push(s);
+        }
+
+        self
+    }
+
+    pub fn start(self) -> Threads {
+        let log = self.parent.log.clone();
+        let page_token = self.resume_from_token.clone();
+        let c = Arc::new(self);
+
+        Pages::new(log, "threads", page_token, move |pt| {
+            fetch_page(Arc::clone(&c), pt)
+        })
+    }
+}
+
+pub type Threads = Pages<Thread>;
+
+async fn fetch_page(
+    c: Arc<ThreadsConfig>,
+    page_token: Option<String>,
+) -> Result<Page<Thread>> {
+    let log = &c.parent.log;
+
+    let url = c.parent.url("users/me/threads");
+
+    let res = c
+        .parent
+        .execute(|| {
+            let mut req = c.parent.client.get(&url);
+
+            if let Some(q) = &c.q {
+                req = req.query(&[("q", q)]);
+            }
+            if c.spamtrash {
+                req = req.query(&[("includeSpamTrash", "true")]);
+            }
+            for l in &c.label_ids {
+                req = req.query(&[("labelIds", l)]);
+            }
+            if let Some(pt) = &page_token {
+                req = req.query(&[("pageToken", pt)]);
+            }
+            if let Some(pp) = &c.perpage {
+                req = req.query(&[("maxResults", pp.to_string())]);
+            }
+
+            req
+        })
+        .await?;
+
+    let o: RThreads = json(res).await?;
+    debug!(log, "result count estimate: {}", o.result_size_estimate);
+
+    Ok((o.threads, o.next_page_token))
+}
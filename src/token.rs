@@ -0,0 +1,267 @@
+/*
+ * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
+ */
+
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::error::Error;
+use super::gauth::GAuth;
+use super::util::temp_path;
+
+/**
+ * A source of OAuth access tokens for use with the Gmail API.  The GAuth
+ * authenticator is one implementation, but consumers may provide their own;
+ * e.g., to obtain tokens from a secrets vault or a metadata server.
+ */
+#[async_trait]
+pub trait TokenSource: Send + Sync {
+    /**
+     * Return an access token that is valid for use right now.  If the source
+     * has to refresh or fetch the token, it should do so here.  Failures that
+     * are not an AuthError may be reported with Error::Other.
+     */
+    async fn token(&self) -> Result<String, Error>;
+}
+
+#[async_trait]
+impl<T: TokenSource + ?Sized> TokenSource for Arc<T> {
+    async fn token(&self) -> Result<String, Error> {
+        self.as_ref().token().await
+    }
+}
+
+#[async_trait]
+impl TokenSource for GAuth {
+    async fn token(&self) -> Result<String, Error> {
+        self.check_refresh().await?;
+        Ok(self.access_token())
+    }
+}
+
+/**
+ * A token source that always returns the same access token, which is useful
+ * for testing or when the token is managed entirely outside this crate.
+ */
+#[derive(Clone)]
+pub struct StaticToken {
+    token: String,
+}
+
+impl StaticToken {
+    pub fn new<S: AsRef<str>>(token: S) -> StaticToken {
+        StaticToken {
+            token: token.as_ref().to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl TokenSource for StaticToken {
+    async fn token(&self) -> Result<String, Error> {
+        Ok(self.token.clone())
+    }
+}
+
+/**
+ * The persistent authentication state of a GAuth.  The expiry time is stored
+ * as seconds since the UNIX epoch.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StoredToken {
+    #[serde(default)]
+    pub refresh_token: String,
+    #[serde(default)]
+    pub access_token: String,
+    #[serde(default)]
+    pub expiry: Option<u64>,
+    /**
+     * The granted scopes, separated by spaces.
+     */
+    #[serde(default)]
+    pub scope: String,
+}
+
+/**
+ * Somewhere to keep authentication state between runs of a program, so that
+ * tokens need not be obtained from scratch each time.  Once the store is in
+ * use by a GAuth, saves and clears are made on the blocking thread pool, so
+ * implementations are free to do blocking I/O.  Failures are reported to the
+ * caller as AuthError::Store.
+ */
+pub trait TokenStore: Send + Sync {
+    /**
+     * Load the stored state, if there is any.
+     */
+    fn load(&self) -> io::Result<Option<StoredToken>>;
+
+    /**
+     * Replace any stored state with this state.
+     */
+    fn save(&self, token: &StoredToken) -> io::Result<()>;
+
+    /**
+     * Remove any stored state.
+     */
+    fn clear(&self) -> io::Result<()>;
+}
+
+/**
+ * A token store that keeps state as JSON in a file.  The file is readable
+ * only by the owner, and is replaced atomically on each save so that a crash
+ * cannot leave a partially written file behind.
+ */
+pub struct FileTokenStore {
+    path: PathBuf,
+    /*
+     * Saves and clears are made one at a time, so that each leaves the file
+     * in a complete state, even when a GAuth makes them from several tasks.
+     */
+    lock: Mutex<()>,
+}
+
+impl FileTokenStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileTokenStore {
+        FileTokenStore {
+            path: path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Include the file name in an error.
+     */
+    fn error<E: std::fmt::Display>(
+        &self,
+        kind: ErrorKind,
+        what: &str,
+        e: E,
+    ) -> io::Error {
+        io::Error::new(
+            kind,
+            format!("{} token file {:?}: {}", what, self.path, e),
+        )
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> io::Result<Option<StoredToken>> {
+        match fs::read(&self.path) {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf).map_err(|e| {
+                self.error(ErrorKind::InvalidData, "parsing", e)
+            })?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(self.error(e.kind(), "reading", e)),
+        }
+    }
+
+    fn save(&self, token: &StoredToken) -> io::Result<()> {
+        let buf = serde_json::to_vec_pretty(token)?;
+
+        let tmp = temp_path(&self.path).ok_or_else(|| {
+            self.error(ErrorKind::InvalidInput, "invalid", "no file name")
+        })?;
+
+        let _lock = self.lock.lock().unwrap();
+
+        /*
+         * Write the new contents into a temporary file in the same directory
+         * and then rename it into place.  The temporary file must be new, so
+         * that it has the permissions we ask for here.
+         */
+        let res = (|| -> io::Result<()> {
+            let mut oo = fs::OpenOptions::new();
+            oo.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                oo.mode(0o600);
+            }
+
+            let mut f = oo.open(&tmp)?;
+            f.write_all(&buf)?;
+            f.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(())
+        })();
+
+        if let Err(e) = res {
+            fs::remove_file(&tmp).ok();
+            return Err(self.error(e.kind(), "writing", e));
+        }
+
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        let _lock = self.lock.lock().unwrap();
+
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(self.error(e.kind(), "removing", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_concurrent_saves() {
+        let dir = std::env::temp_dir()
+            .join(format!("rgmail-store-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token.json");
+        let store = Arc::new(FileTokenStore::new(&path));
+
+        /*
+         * Saves that overlap must each write their own temporary file, and
+         * leave a complete state behind.
+         */
+        let threads = (0..16)
+            .map(|n| {
+                let store = Arc::clone(&store);
+                std::thread::spawn(move || {
+                    let st = StoredToken {
+                        refresh_token: format!("refresh-{}", n),
+                        access_token: "a".repeat(4096),
+                        ..Default::default()
+                    };
+                    for _ in 0..10 {
+                        store.save(&st).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let st = store.load().unwrap().unwrap();
+        assert!(st.refresh_token.starts_with("refresh-"));
+        assert_eq!(st.access_token.len(), 4096);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
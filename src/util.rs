@@ -2,10 +2,50 @@
  * Copyright 2022 Joshua M. Clulow <josh@sysmgr.org>
  */
 
-pub fn bu(s: &str) -> String {
-    format!("https://www.googleapis.com/gmail/v1/{}", s)
+use std::path::{Path, PathBuf};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+pub const BASE_URL: &str = "https://www.googleapis.com/gmail/v1";
+pub const BATCH_URL: &str = "https://www.googleapis.com/batch/gmail/v1";
+
+/**
+ * The maximum number of requests that Gmail will accept in a single batch.
+ */
+pub const MAX_BATCH: usize = 100;
+
+/**
+ * The maximum number of messages in a single batchModify or batchDelete call.
+ */
+pub const MAX_BATCH_MODIFY: usize = 1000;
+
+/**
+ * Append a relative path to a base URL, which may or may not have a trailing
+ * slash.
+ */
+pub fn join(base: &str, s: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), s)
 }
 
-pub fn bbu() -> String {
-    "https://www.googleapis.com/batch/gmail/v1".to_string()
+/**
+ * Choose a name for a temporary file in the same directory as the specified
+ * file, to be renamed over it once complete.  The name includes a random
+ * suffix, so that concurrent writers in the same process each get their own;
+ * the file must still be opened with create_new(true), so that we never
+ * write into a file that someone else has made.  Returns None if the path
+ * has no file name.
+ */
+pub fn temp_path(path: &Path) -> Option<PathBuf> {
+    let fname = path.file_name()?;
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+
+    let mut tmpname = std::ffi::OsString::from(".");
+    tmpname.push(fname);
+    tmpname.push(format!(".{}.{}.tmp", std::process::id(), suffix));
+    Some(path.with_file_name(tmpname))
 }